@@ -1,3 +1,6 @@
+use std::time::Duration;
+
+use ipnet::IpNet;
 use serde::Deserialize;
 use tokio::io::AsyncReadExt;
 
@@ -6,9 +9,17 @@ pub struct Config {
     admin: Vec<i64>,
     totp: String,
     database: String,
+    #[serde(default = "default_database_timeout")]
+    database_timeout: u64,
     #[serde(default)]
     web: Web,
     platform: Upstream,
+    #[serde(default)]
+    webhook: Vec<Webhook>,
+}
+
+fn default_database_timeout() -> u64 {
+    10
 }
 
 impl Config {
@@ -36,6 +47,15 @@ impl Config {
         &self.database
     }
 
+    // Deadline of every database request
+    pub fn database_timeout(&self) -> Duration {
+        Duration::from_secs(self.database_timeout)
+    }
+
+    pub fn webhooks(&self) -> &[Webhook] {
+        &self.webhook
+    }
+
     pub fn get_totp(&self) -> anyhow::Result<totp_rs::TOTP> {
         Ok(totp_rs::TOTP::new(
             totp_rs::Algorithm::SHA256,
@@ -70,12 +90,65 @@ impl Upstream {
     }
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct Webhook {
+    url: String,
+    secret: String,
+    #[serde(default = "default_webhook_retries")]
+    retries: u32,
+}
+
+fn default_webhook_retries() -> u32 {
+    5
+}
+
+impl Webhook {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Web {
     enabled: bool,
     bind: String,
+    socket_mode: Option<u32>,
     prefix: Option<String>,
-    access_key: String,
+    #[serde(default = "default_fr_threshold")]
+    fr_threshold: usize,
+    #[serde(default)]
+    trusted_proxies: Vec<IpNet>,
+    tls: Option<Tls>,
+    #[serde(default = "default_auth_timeout")]
+    auth_timeout: u64,
+    #[serde(default = "default_heartbeat")]
+    heartbeat: u64,
+    #[serde(default)]
+    lockout: Lockout,
+    #[serde(default)]
+    dispatch: Dispatch,
+    #[serde(default)]
+    admin: Vec<String>,
+}
+
+fn default_fr_threshold() -> usize {
+    3
+}
+
+fn default_auth_timeout() -> u64 {
+    30
+}
+
+fn default_heartbeat() -> u64 {
+    30
 }
 
 impl Web {
@@ -87,12 +160,47 @@ impl Web {
         &self.bind
     }
 
+    // Permission bits of unix socket file, e.g. 0o660
+    pub fn socket_mode(&self) -> Option<u32> {
+        self.socket_mode
+    }
+
     pub fn prefix(&self) -> Option<&String> {
         self.prefix.as_ref()
     }
 
-    pub fn access_key(&self) -> &str {
-        &self.access_key
+    pub fn fr_threshold(&self) -> usize {
+        self.fr_threshold
+    }
+
+    pub fn trusted_proxies(&self) -> &[IpNet] {
+        &self.trusted_proxies
+    }
+
+    pub fn tls(&self) -> Option<&Tls> {
+        self.tls.as_ref()
+    }
+
+    pub fn auth_timeout(&self) -> Duration {
+        Duration::from_secs(self.auth_timeout)
+    }
+
+    // None if heartbeat is disabled
+    pub fn heartbeat(&self) -> Option<Duration> {
+        (self.heartbeat > 0).then(|| Duration::from_secs(self.heartbeat))
+    }
+
+    pub fn lockout(&self) -> &Lockout {
+        &self.lockout
+    }
+
+    pub fn dispatch(&self) -> &Dispatch {
+        &self.dispatch
+    }
+
+    // Client codenames allowed to use admin endpoints
+    pub fn admin(&self) -> &[String] {
+        &self.admin
     }
 }
 
@@ -101,8 +209,121 @@ impl Default for Web {
         Self {
             enabled: false,
             bind: "0.0.0.0:26511".to_string(),
+            socket_mode: None,
             prefix: None,
-            access_key: "114514".to_string(),
+            fr_threshold: default_fr_threshold(),
+            trusted_proxies: Vec::new(),
+            tls: None,
+            auth_timeout: default_auth_timeout(),
+            heartbeat: default_heartbeat(),
+            lockout: Lockout::default(),
+            dispatch: Dispatch::default(),
+            admin: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Tls {
+    cert: String,
+    key: String,
+}
+
+impl Tls {
+    pub fn cert(&self) -> &str {
+        &self.cert
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Lockout {
+    threshold: u32,
+    base: u64,
+    max: u64,
+    alert: Option<u32>,
+}
+
+impl Lockout {
+    // Failures before first lockout
+    pub fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    pub fn base(&self) -> Duration {
+        Duration::from_secs(self.base)
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_secs(self.max)
+    }
+
+    // Failures that trigger a Telegram alert to admins
+    pub fn alert(&self) -> Option<u32> {
+        self.alert
+    }
+}
+
+impl Default for Lockout {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            base: 30,
+            max: 3600,
+            alert: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DispatchMode {
+    #[default]
+    Broadcast,
+    Targeted,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Dispatch {
+    mode: DispatchMode,
+    per_code: usize,
+    per_cookie: usize,
+    job_timeout: u64,
+}
+
+impl Dispatch {
+    pub fn mode(&self) -> DispatchMode {
+        self.mode
+    }
+
+    // Cookies assigned to each code
+    pub fn per_code(&self) -> usize {
+        self.per_code
+    }
+
+    // Unfinished jobs a cookie can hold at once
+    pub fn per_cookie(&self) -> usize {
+        self.per_cookie
+    }
+
+    // Job without result report is considered finished after this
+    pub fn job_timeout(&self) -> Duration {
+        Duration::from_secs(self.job_timeout)
+    }
+}
+
+impl Default for Dispatch {
+    fn default() -> Self {
+        Self {
+            mode: DispatchMode::Broadcast,
+            per_code: 1,
+            per_cookie: 5,
+            job_timeout: 300,
         }
     }
 }
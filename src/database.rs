@@ -1,17 +1,176 @@
-use futures_util::StreamExt as _;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use futures_util::{StreamExt as _, future::BoxFuture};
 use log::{error, info};
-use sqlx::{sqlite::SqliteConnectOptions, Connection, SqliteConnection};
+use sqlx::{
+    ConnectOptions as _, Connection as _, SqliteConnection, SqlitePool,
+    error::ErrorKind,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
+};
+
+pub mod v2 {
+    pub async fn migration_v1(conn: &mut sqlx::SqliteConnection) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE "history_v2" (
+                "entry_id" INTEGER NOT NULL,
+                "timestamp" INTEGER NOT NULL,
+                "id"        TEXT NOT NULL,
+                "code"      TEXT NOT NULL,
+                "error"     TEXT,
+                PRIMARY KEY("entry_id" AUTOINCREMENT)
+            );
+        "#,
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        sqlx::query(r#"INSERT INTO "history_v2" ("timestamp", "id", "code", "error") SELECT "timestamp", "id", "code", "error" FROM "history""#).execute(&mut *conn).await?;
+
+        sqlx::query(r#"DROP TABLE "history""#)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query(r#"ALTER TABLE "history_v2" RENAME TO "history""#)
+            .execute(&mut *conn)
+            .await?;
 
-pub mod v1 {
-    pub const VERSION: &str = "1";
+        Ok(())
+    }
 }
 
-pub mod v2 {
+pub mod v3 {
+    pub async fn migration_v2(conn: &mut sqlx::SqliteConnection) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE "codes_v3" (
+                "seq"	INTEGER NOT NULL,
+                "code"	TEXT NOT NULL UNIQUE,
+                "message_id"	INTEGER NOT NULL UNIQUE,
+                "fr"	INTEGER NOT NULL DEFAULT 0,
+                "timestamp"	INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY("seq" AUTOINCREMENT)
+            );
+        "#,
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        sqlx::query(r#"INSERT INTO "codes_v3" ("code", "message_id", "fr") SELECT "code", "message_id", "fr" FROM "codes" ORDER BY "rowid""#).execute(&mut *conn).await?;
+
+        sqlx::query(r#"DROP TABLE "codes""#)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query(r#"ALTER TABLE "codes_v3" RENAME TO "codes""#)
+            .execute(&mut *conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+pub mod v4 {
+    pub async fn migration_v3(conn: &mut sqlx::SqliteConnection) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE "clients" (
+                "codename"	TEXT NOT NULL,
+                "hash"	TEXT NOT NULL,
+                "created"	INTEGER NOT NULL,
+                PRIMARY KEY("codename")
+            );
+        "#,
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+}
+
+pub mod v5 {
+    pub async fn migration_v4(conn: &mut sqlx::SqliteConnection) -> sqlx::Result<()> {
+        sqlx::query(r#"ALTER TABLE "history" ADD COLUMN "reward" TEXT"#)
+            .execute(&mut *conn)
+            .await?;
+        // Authenticated stream client which sent the report, codename inside a report is unverified
+        sqlx::query(r#"ALTER TABLE "history" ADD COLUMN "reporter" TEXT"#)
+            .execute(&mut *conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+pub mod v6 {
+    pub async fn migration_v5(conn: &mut sqlx::SqliteConnection) -> sqlx::Result<()> {
+        sqlx::query(r#"ALTER TABLE "clients" ADD COLUMN "last_seen" INTEGER"#)
+            .execute(&mut *conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+pub mod v7 {
+    pub async fn migration_v6(conn: &mut sqlx::SqliteConnection) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE "webhook_deliveries" (
+                "id"	INTEGER NOT NULL,
+                "url"	TEXT NOT NULL,
+                "event"	TEXT NOT NULL,
+                "code"	TEXT NOT NULL,
+                "attempt"	INTEGER NOT NULL,
+                "status"	INTEGER,
+                "error"	TEXT,
+                "timestamp"	INTEGER NOT NULL,
+                PRIMARY KEY("id" AUTOINCREMENT)
+            );
+        "#,
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+}
+
+pub mod v8 {
+    // Codes waiting for their message to be posted, kept apart so seq follows publish order
+    pub async fn migration_v7(conn: &mut sqlx::SqliteConnection) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE "code_reservations" (
+                "code"	TEXT NOT NULL,
+                "timestamp"	INTEGER NOT NULL,
+                PRIMARY KEY("code")
+            );
+        "#,
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+}
+
+pub mod v9 {
+    use crate::types::{ClientCredential, CodeEvent};
+
     pub const CREATE_STATEMENT: &str = r#"
         CREATE TABLE "codes" (
+            "seq"	INTEGER NOT NULL,
             "code"	TEXT NOT NULL UNIQUE,
             "message_id"	INTEGER NOT NULL UNIQUE,
             "fr"	INTEGER NOT NULL DEFAULT 0,
+            "timestamp"	INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY("seq" AUTOINCREMENT)
+        );
+
+        CREATE TABLE "code_reservations" (
+            "code"	TEXT NOT NULL,
+            "timestamp"	INTEGER NOT NULL,
             PRIMARY KEY("code")
         );
 
@@ -43,21 +202,55 @@ pub mod v2 {
             "id"        TEXT NOT NULL,
             "code"      TEXT NOT NULL,
             "error"     TEXT,
+            "reward"    TEXT,
+            "reporter"  TEXT,
 	        PRIMARY KEY("entry_id" AUTOINCREMENT)
         );
+
+        CREATE TABLE "clients" (
+            "codename"	TEXT NOT NULL,
+            "setting"	TEXT NOT NULL,
+            "stored_key"	TEXT NOT NULL,
+            "token_hash"	TEXT NOT NULL,
+            "created"	INTEGER NOT NULL,
+            "last_seen"	INTEGER,
+            PRIMARY KEY("codename")
+        );
+
+        CREATE TABLE "webhook_deliveries" (
+            "id"	INTEGER NOT NULL,
+            "url"	TEXT NOT NULL,
+            "event"	TEXT NOT NULL,
+            "code"	TEXT NOT NULL,
+            "attempt"	INTEGER NOT NULL,
+            "status"	INTEGER,
+            "error"	TEXT,
+            "timestamp"	INTEGER NOT NULL,
+            PRIMARY KEY("id" AUTOINCREMENT)
+        );
     "#;
 
-    pub const VERSION: &str = "2";
+    pub const VERSION: &str = "9";
 
-    #[derive(Clone)]
+    #[derive(Clone, Debug)]
     pub enum BroadcastEvent {
-        NewCode(String),
+        NewCode(CodeEvent),
+        Resend(CodeEvent),
+        Fr(CodeEvent),
         Exit,
     }
 
     impl BroadcastEvent {
-        pub fn new_code(code: &str) -> Self {
-            Self::NewCode(code.to_string())
+        pub fn new_code(code: CodeEvent) -> Self {
+            Self::NewCode(code)
+        }
+
+        pub fn resend(code: CodeEvent) -> Self {
+            Self::Resend(code)
+        }
+
+        pub fn fr(code: CodeEvent) -> Self {
+            Self::Fr(code)
         }
 
         pub fn exit() -> Self {
@@ -65,31 +258,47 @@ pub mod v2 {
         }
     }
 
-    pub async fn migration_v1(conn: &mut sqlx::SqliteConnection) -> sqlx::Result<()> {
+    // Stored argon2 output answers challenges like a password, keep only keys derived from it
+    pub async fn migration_v8(conn: &mut sqlx::SqliteConnection) -> sqlx::Result<()> {
         sqlx::query(
             r#"
-            CREATE TABLE "history_v2" (
-                "entry_id" INTEGER NOT NULL,
-                "timestamp" INTEGER NOT NULL,
-                "id"        TEXT NOT NULL,
-                "code"      TEXT NOT NULL,
-                "error"     TEXT,
-                PRIMARY KEY("entry_id" AUTOINCREMENT)
+            CREATE TABLE "clients_v9" (
+                "codename"	TEXT NOT NULL,
+                "setting"	TEXT NOT NULL,
+                "stored_key"	TEXT NOT NULL,
+                "token_hash"	TEXT NOT NULL,
+                "created"	INTEGER NOT NULL,
+                "last_seen"	INTEGER,
+                PRIMARY KEY("codename")
             );
         "#,
         )
         .execute(&mut *conn)
         .await?;
 
-        sqlx::query(r#"INSERT INTO "history_v2" ("timestamp", "id", "code", "error") SELECT "timestamp", "id", "code", "error" FROM "history""#).execute(&mut *conn).await?;
+        let clients: Vec<(String, String, i64, Option<i64>)> =
+            sqlx::query_as(r#"SELECT "codename", "hash", "created", "last_seen" FROM "clients""#)
+                .fetch_all(&mut *conn)
+                .await?;
+        for (codename, hash, created, last_seen) in clients {
+            let (credential, _) = ClientCredential::from_hash(&hash).ok_or_else(|| {
+                sqlx::Error::Protocol(format!("Invalid credential of {codename}"))
+            })?;
+            sqlx::query(r#"INSERT INTO "clients_v9" VALUES (?, ?, ?, ?, ?, ?)"#)
+                .bind(codename)
+                .bind(credential.setting())
+                .bind(credential.stored_key())
+                .bind(credential.token_hash())
+                .bind(created)
+                .bind(last_seen)
+                .execute(&mut *conn)
+                .await?;
+        }
 
-        sqlx::query(r#"DROP TABLE "history""#)
+        sqlx::query(r#"DROP TABLE "clients""#)
             .execute(&mut *conn)
             .await?;
-        sqlx::query(r#"ALTER TABLE "history_v2" RENAME TO "history""#)
-            .execute(&mut *conn)
-            .await?;
-        sqlx::query(r#"UPDATE "meta" SET "value" = '2' WHERE "key" = 'version' "#)
+        sqlx::query(r#"ALTER TABLE "clients_v9" RENAME TO "clients""#)
             .execute(&mut *conn)
             .await?;
 
@@ -97,9 +306,56 @@ pub mod v2 {
     }
 }
 
-#[derive(Debug)]
+type MigrationFn = for<'c> fn(&'c mut SqliteConnection) -> BoxFuture<'c, sqlx::Result<()>>;
+
+struct Migration {
+    version: u32,
+    run: MigrationFn,
+}
+
+// Ordered by version, each one upgrades database from the previous version
+const MIGRATIONS: [Migration; 8] = [
+    Migration {
+        version: 2,
+        run: |conn| Box::pin(v2::migration_v1(conn)),
+    },
+    Migration {
+        version: 3,
+        run: |conn| Box::pin(v3::migration_v2(conn)),
+    },
+    Migration {
+        version: 4,
+        run: |conn| Box::pin(v4::migration_v3(conn)),
+    },
+    Migration {
+        version: 5,
+        run: |conn| Box::pin(v5::migration_v4(conn)),
+    },
+    Migration {
+        version: 6,
+        run: |conn| Box::pin(v6::migration_v5(conn)),
+    },
+    Migration {
+        version: 7,
+        run: |conn| Box::pin(v7::migration_v6(conn)),
+    },
+    Migration {
+        version: 8,
+        run: |conn| Box::pin(v8::migration_v7(conn)),
+    },
+    Migration {
+        version: 9,
+        run: |conn| Box::pin(v9::migration_v8(conn)),
+    },
+];
+
+// Seconds a code stays reserved without its message being recorded
+const RESERVATION_TTL: i64 = 600;
+
+// Writes are applied one by one by the actor, reads run concurrently on the pool
+#[derive(Clone, Debug)]
 pub struct Database {
-    conn: sqlx::SqliteConnection,
+    pool: SqlitePool,
     broadcast: broadcast::Sender<current::BroadcastEvent>,
     init: bool,
 }
@@ -144,104 +400,236 @@ pub trait DatabaseCheckExt {
 }
 
 impl Database {
+    const POOL_SIZE: u32 = 4;
+    const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
     pub async fn connect(
         database: &str,
         broadcast: broadcast::Sender<current::BroadcastEvent>,
     ) -> DBResult<Self> {
-        let conn = SqliteConnection::connect_with(
-            &SqliteConnectOptions::new()
-                .create_if_missing(true)
-                .filename(database),
-        )
-        .await?;
+        let pool = SqlitePoolOptions::new()
+            .max_connections(Self::POOL_SIZE)
+            .connect_with(
+                SqliteConnectOptions::new()
+                    .create_if_missing(true)
+                    .filename(database)
+                    .journal_mode(SqliteJournalMode::Wal)
+                    .synchronous(SqliteSynchronous::Normal)
+                    .busy_timeout(Self::BUSY_TIMEOUT),
+            )
+            .await?;
         Ok(Self {
-            conn,
+            pool,
             init: false,
             broadcast,
         })
     }
 
-    async fn migration(&mut self) -> sqlx::Result<bool> {
-        if self
+    // Migrations bringing database from `from` up to `to`, latest if None
+    fn plan(from: u32, to: Option<u32>) -> anyhow::Result<Vec<&'static Migration>> {
+        let latest: u32 = current::VERSION.parse()?;
+        let to = to.unwrap_or(latest);
+        if to > latest {
+            return Err(anyhow!("Unknown version {to}, latest is {latest}"));
+        }
+        if to < from {
+            return Err(anyhow!(
+                "Database is at v{from}, downgrade to v{to} is not supported"
+            ));
+        }
+        Ok(MIGRATIONS
+            .iter()
+            .filter(|migration| migration.version > from && migration.version <= to)
+            .collect())
+    }
+
+    async fn version(conn: &mut SqliteConnection) -> anyhow::Result<u32> {
+        let version = conn
             .check_database_version()
             .await?
-            .is_some_and(|x| x.eq(v1::VERSION))
-        {
-            v2::migration_v1(&mut self.conn).await?;
-            log::info!("Migration database to v2");
-            return Ok(true);
+            .ok_or_else(|| anyhow!("Database version not found"))?;
+        version
+            .parse()
+            .map_err(|_| anyhow!("Invalid database version {version:?}"))
+    }
+
+    // Each step and its version bump are committed together, a failed step leaves previous version intact
+    async fn migrate(conn: &mut SqliteConnection, to: Option<u32>) -> anyhow::Result<Vec<u32>> {
+        let plan = Self::plan(Self::version(conn).await?, to)?;
+        let mut applied = Vec::with_capacity(plan.len());
+        for migration in plan {
+            let mut transaction = conn.begin().await?;
+            (migration.run)(&mut transaction).await?;
+            sqlx::query(r#"UPDATE "meta" SET "value" = ? WHERE "key" = 'version'"#)
+                .bind(migration.version.to_string())
+                .execute(&mut *transaction)
+                .await?;
+            transaction.commit().await?;
+            info!("Migration database to v{}", migration.version);
+            applied.push(migration.version);
         }
-        Ok(false)
+        Ok(applied)
     }
 
-    pub async fn init(&mut self) -> sqlx::Result<bool> {
+    pub async fn init(&mut self) -> anyhow::Result<bool> {
         self.init = true;
-        if !self.check_database_table().await? {
-            self.create_db().await?;
-            self.insert_database_version().await?;
+        let mut conn = self.pool.acquire().await?;
+        if !conn.check_database_table().await? {
+            conn.create_db().await?;
+            conn.insert_database_version().await?;
         }
-        self.migration().await
+        let migrated = !Self::migrate(&mut conn, None).await?.is_empty();
+        // Reservation left by a previous run never got its message posted
+        sqlx::query(r#"DELETE FROM "code_reservations""#)
+            .execute(&mut *conn)
+            .await?;
+        Ok(migrated)
     }
 
-    pub async fn _check_auth(&mut self, user: i64) -> sqlx::Result<bool> {
+    pub async fn _check_auth(&self, user: i64) -> sqlx::Result<bool> {
         if user < 0 {
             return Ok(false);
         }
         Ok(
             sqlx::query(r#"SELECT 1 FROM "users" WHERE "id" = ? AND "authorized" = 1"#)
                 .bind(user)
-                .fetch_optional(&mut self.conn)
+                .fetch_optional(&self.pool)
                 .await?
                 .is_some(),
         )
     }
 
-    pub async fn query_code(&mut self, code: &str) -> DBResult<Option<CodeRow>> {
-        sqlx::query_as(r#"SELECT * FROM "codes" WHERE "code" = ? "#)
+    pub async fn query_code(&self, code: &str) -> DBResult<Option<CodeRow>> {
+        sqlx::query_as(r#"SELECT * FROM "codes" WHERE "code" = ?"#)
             .bind(code)
-            .fetch_optional(&mut self.conn)
+            .fetch_optional(&self.pool)
             .await
     }
 
-    pub async fn insert_code(&mut self, code: &str, message_id: i32) -> DBResult<()> {
-        sqlx::query(r#"INSERT INTO "codes" VALUES (?, ?, 0)"#)
+    pub async fn query_code_since(
+        &self,
+        seq: Option<i64>,
+        timestamp: Option<i64>,
+    ) -> DBResult<Vec<CodeRow>> {
+        // Seq is exact and wins, timestamp only has second resolution so that second is sent again
+        let timestamp = if seq.is_some() { None } else { timestamp };
+        sqlx::query_as(
+            r#"SELECT * FROM "codes" WHERE "seq" > ? AND "timestamp" >= ? ORDER BY "seq""#,
+        )
+        .bind(seq.unwrap_or(0))
+        .bind(timestamp.unwrap_or(i64::MIN))
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    // None if the code is newly reserved, otherwise whether the existing one is FR
+    pub async fn reserve_code(&self, code: &str) -> DBResult<Option<bool>> {
+        let timestamp = kstool::time::get_current_second() as i64;
+        let mut tx = self.pool.begin().await?;
+        // Submitter whose request timed out never releases it, so stale reservations expire
+        sqlx::query(r#"DELETE FROM "code_reservations" WHERE "timestamp" < ?"#)
+            .bind(timestamp - RESERVATION_TTL)
+            .execute(&mut *tx)
+            .await?;
+        if let Some((fr,)) =
+            sqlx::query_as::<_, (bool,)>(r#"SELECT "fr" FROM "codes" WHERE "code" = ?"#)
+                .bind(code)
+                .fetch_optional(&mut *tx)
+                .await?
+        {
+            return Ok(Some(fr));
+        }
+        let reserved = sqlx::query(
+            r#"INSERT INTO "code_reservations" VALUES (?, ?) ON CONFLICT ("code") DO NOTHING"#,
+        )
+        .bind(code)
+        .bind(timestamp)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        tx.commit().await?;
+        Ok((!reserved).then_some(false))
+    }
+
+    // Record posted code and drop its reservation, seq is assigned here so it follows publish order.
+    // Repeating it is harmless, a code already recorded is left untouched
+    pub async fn confirm_code(&self, code: &str, message_id: i32) -> DBResult<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM "code_reservations" WHERE "code" = ?"#)
             .bind(code)
-            .bind(message_id)
-            .execute(&mut self.conn)
+            .execute(&mut *tx)
             .await?;
-        self.broadcast
-            .send(current::BroadcastEvent::new_code(code))
-            .ok()
-            .tap_none(|| error!("Unable send broadcast"));
+        let row: Option<CodeRow> = sqlx::query_as(
+            r#"INSERT INTO "codes" ("code", "message_id", "fr", "timestamp") VALUES (?, ?, 0, ?) ON CONFLICT ("code") DO NOTHING RETURNING *"#,
+        )
+        .bind(code)
+        .bind(message_id)
+        .bind(kstool::time::get_current_second() as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        if let Some(row) = row {
+            self.broadcast
+                .send(current::BroadcastEvent::new_code(row.into()))
+                .ok()
+                .tap_none(|| error!("Unable send broadcast"));
+        }
         Ok(())
     }
 
-    pub async fn set_code_fr(&mut self, code: &str, is_fr: bool) -> DBResult<()> {
-        sqlx::query(r#"UPDATE "codes" SET "fr" = ? WHERE "code" = ?"#)
-            .bind(is_fr)
+    // Keep reservation of a posted code alive while its record is retried, so it cannot expire and
+    // be posted again. Reserved anew if it was dropped already
+    pub async fn hold_code(&self, code: &str) -> DBResult<()> {
+        sqlx::query(
+            r#"INSERT INTO "code_reservations" VALUES (?, ?) ON CONFLICT ("code") DO UPDATE SET "timestamp" = "excluded"."timestamp""#,
+        )
+        .bind(code)
+        .bind(kstool::time::get_current_second() as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn release_code(&self, code: &str) -> DBResult<()> {
+        sqlx::query(r#"DELETE FROM "code_reservations" WHERE "code" = ?"#)
             .bind(code)
-            .execute(&mut self.conn)
+            .execute(&self.pool)
             .await?;
         Ok(())
     }
 
-    pub async fn query_user(&mut self, user: i64) -> DBResult<Option<User>> {
+    // Returns whether the flag actually changed
+    pub async fn set_code_fr(&self, code: &str, is_fr: bool) -> DBResult<bool> {
+        Ok(
+            sqlx::query(r#"UPDATE "codes" SET "fr" = ? WHERE "code" = ? AND "fr" != ?"#)
+                .bind(is_fr)
+                .bind(code)
+                .bind(is_fr)
+                .execute(&self.pool)
+                .await?
+                .rows_affected()
+                > 0,
+        )
+    }
+
+    pub async fn query_user(&self, user: i64) -> DBResult<Option<User>> {
         sqlx::query_as(r#"SELECT * FROM "users" WHERE "id" = ?"#)
             .bind(user)
-            .fetch_optional(&mut self.conn)
+            .fetch_optional(&self.pool)
             .await
     }
 
-    pub async fn insert_user(&mut self, user: i64, level: AccessLevel) -> DBResult<()> {
+    pub async fn insert_user(&self, user: i64, level: AccessLevel) -> DBResult<()> {
         sqlx::query(r#"INSERT INTO "users" VALUES (?, ?)"#)
             .bind(user)
             .bind(level.i32())
-            .execute(&mut self.conn)
+            .execute(&self.pool)
             .await?;
         Ok(())
     }
 
-    pub async fn set_authorized_status(&mut self, user: i64, level: AccessLevel) -> DBResult<()> {
+    pub async fn set_authorized_status(&self, user: i64, level: AccessLevel) -> DBResult<()> {
         match self.query_user(user).await
         //.tap(|u| log::debug!("{u:?}"))
         ? {
@@ -252,7 +640,7 @@ impl Database {
                 sqlx::query(r#"UPDATE "users" SET "authorized" = ? WHERE "id" = ?"#)
                     .bind(level.i32())
                     .bind(user)
-                    .execute(&mut self.conn)
+                    .execute(&self.pool)
                     .await?;
                 Ok(())
             }
@@ -261,7 +649,7 @@ impl Database {
     }
 
     pub async fn cookie_set(
-        &mut self,
+        &self,
         user: i64,
         csrf: &str,
         session: &str,
@@ -278,7 +666,7 @@ impl Database {
                 .bind(csrf)
                 .bind(session)
                 .bind(id)
-                .execute(&mut self.conn)
+                .execute(&self.pool)
                 .await?;
             }
             None => {
@@ -287,203 +675,580 @@ impl Database {
                     .bind(csrf)
                     .bind(session)
                     .bind(user)
-                    .execute(&mut self.conn)
+                    .execute(&self.pool)
                     .await?;
             }
         }
         Ok(true)
     }
 
-    pub async fn cookie_usable(&mut self, id: &str, usable: bool) -> DBResult<()> {
+    pub async fn cookie_usable(&self, id: &str, usable: bool) -> DBResult<()> {
         sqlx::query(r#"UPDATE "cookies" SET "enabled" = ? WHERE "id" = ?"#)
             .bind(usable)
             .bind(id)
-            .execute(&mut self.conn)
+            .execute(&self.pool)
             .await?;
         Ok(())
     }
 
-    pub async fn cookie_update_timestamp(&mut self, id: &str) -> DBResult<()> {
+    pub async fn cookie_update_timestamp(&self, id: &str) -> DBResult<()> {
         sqlx::query(r#"UPDATE "cookies" SET "last_login" = ? WHERE "id" = ?"#)
             .bind(kstool::time::get_current_second() as i64)
             .bind(id)
-            .execute(&mut self.conn)
+            .execute(&self.pool)
             .await?;
         Ok(())
     }
 
-    pub async fn cookie_query(&mut self, id: &str) -> DBResult<Option<Cookie>> {
+    pub async fn cookie_query(&self, id: &str) -> DBResult<Option<Cookie>> {
         sqlx::query_as(r#"SELECT * FROM "cookies" WHERE "id" = ?"#)
             .bind(id)
-            .fetch_optional(&mut self.conn)
+            .fetch_optional(&self.pool)
             .await
     }
 
-    pub async fn cookie_query_user(&mut self, id: i64) -> DBResult<Vec<Cookie>> {
+    pub async fn cookie_query_user(&self, id: i64) -> DBResult<Vec<Cookie>> {
         sqlx::query_as(r#"SELECT * FROM "cookies" WHERE "belong" = ?"#)
             .bind(id)
-            .fetch_all(&mut self.conn)
+            .fetch_all(&self.pool)
             .await
     }
 
-    pub async fn cookie_query_all_enabled(&mut self) -> DBResult<Vec<Cookie>> {
+    pub async fn cookie_query_all_enabled(&self) -> DBResult<Vec<Cookie>> {
         sqlx::query_as(r#"SELECT* FROM "cookies"  WHERE "enabled" = 1"#)
-            .fetch_all(&mut self.conn)
+            .fetch_all(&self.pool)
             .await
     }
 
-    pub async fn cookie_query_all(&mut self) -> DBResult<Vec<Cookie>> {
+    pub async fn cookie_query_all(&self) -> DBResult<Vec<Cookie>> {
         sqlx::query_as(r#"SELECT* FROM "cookies""#)
-            .fetch_all(&mut self.conn)
+            .fetch_all(&self.pool)
             .await
     }
 
-    pub async fn v_query(&mut self) -> DBResult<Option<VStats>> {
+    pub async fn v_query(&self) -> DBResult<Option<VStats>> {
         Ok(
             sqlx::query_as::<_, MetaRow>(r#"SELECT * FROM "meta" WHERE "key" = 'intel_v'"#)
-                .fetch_optional(&mut self.conn)
+                .fetch_optional(&self.pool)
                 .await?
                 .and_then(|s| serde_json::from_str(s.value()).ok()),
         )
     }
 
-    pub async fn v_update(&mut self, v: String) -> DBResult<()> {
+    pub async fn v_update(&self, v: String) -> DBResult<()> {
         if let Some(db_v) = self.v_query().await? {
             if v.eq(db_v.v()) {
                 return Ok(());
             }
             sqlx::query(r#"UPDATE "meta" SET "value" = ? WHERE "key" = 'intel_v'"#)
                 .bind(VStats::new(v).json())
-                .execute(&mut self.conn)
+                .execute(&self.pool)
                 .await
         } else {
             sqlx::query(r#"INSERT INTO "meta" VALUES ('intel_v', ?)"#)
                 .bind(VStats::new(v).json())
-                .execute(&mut self.conn)
+                .execute(&self.pool)
                 .await
         }?;
         Ok(())
     }
 
-    pub async fn log_add(&mut self, id: &str, code: &str, error: Option<String>) -> DBResult<()> {
+    pub async fn log_add(
+        &self,
+        id: &str,
+        code: &str,
+        error: Option<String>,
+        reward: Option<String>,
+        reporter: Option<String>,
+    ) -> DBResult<()> {
         sqlx::query(
-            r#"INSERT INTO "history" ("timestamp", "id", "code", "error") VALUES (?, ?, ?, ?)"#,
+            r#"INSERT INTO "history" ("timestamp", "id", "code", "error", "reward", "reporter") VALUES (?, ?, ?, ?, ?, ?)"#,
         )
         .bind(kstool::time::get_current_second() as i64)
         .bind(id)
         .bind(code)
-        .bind(error)
-        .execute(&mut self.conn)
+        .bind(error.as_ref())
+        .bind(reward)
+        .bind(reporter)
+        .execute(&self.pool)
         .await?;
+        if error.is_some() {
+            // Codename in a report comes from the client, unknown ones share a single series
+            let known = sqlx::query(r#"SELECT 1 FROM "cookies" WHERE LOWER("id") = LOWER(?)"#)
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?
+                .is_some();
+            METRICS.history_error(if known { id } else { "unknown" });
+        }
         Ok(())
     }
 
-    pub async fn log_query(&mut self, id: &str) -> DBResult<Vec<HistoryRow>> {
+    pub async fn log_query(&self, id: &str) -> DBResult<Vec<HistoryRow>> {
         sqlx::query_as(
-            r#"SELECT "timestamp", "id", "code", "error" FROM "history" WHERE "id" = ? ORDER BY "entry_id" DESC LIMIT 20"#,
+            r#"SELECT "timestamp", "id", "code", "error", "reward" FROM "history" WHERE "id" = ? ORDER BY "entry_id" DESC LIMIT 20"#,
         )
         .bind(id)
-        .fetch_all(&mut self.conn)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    // Distinct reporters, so one client cannot reach the threshold with made up codenames.
+    // Entries without reporter are written by this server and counted by agent
+    pub async fn log_count(&self, code: &str, error: &str) -> DBResult<usize> {
+        sqlx::query_as::<_, (i64,)>(
+            r#"SELECT COUNT(DISTINCT COALESCE("reporter", "id")) FROM "history" WHERE "code" = ? AND "error" = ?"#,
+        )
+        .bind(code)
+        .bind(error)
+        .fetch_one(&self.pool)
         .await
+        .map(|(count,)| count as usize)
     }
 
-    pub async fn log_query_all(&mut self) -> DBResult<Vec<HistoryRow>> {
-        sqlx::query_as(r#"SELECT "timestamp", "id", "code", "error" FROM "history" ORDER BY "entry_id" DESC LIMIT 40"#)
-            .fetch_all(&mut self.conn)
+    // Agents already attempted this code, whatever the result
+    pub async fn log_query_code(&self, code: &str) -> DBResult<Vec<String>> {
+        sqlx::query_as::<_, (String,)>(
+            r#"SELECT DISTINCT LOWER("id") FROM "history" WHERE "code" = ?"#,
+        )
+        .bind(code)
+        .fetch_all(&self.pool)
+        .await
+        .map(|rows| rows.into_iter().map(|(id,)| id).collect())
+    }
+
+    pub async fn log_query_all(&self) -> DBResult<Vec<HistoryRow>> {
+        sqlx::query_as(r#"SELECT "timestamp", "id", "code", "error", "reward" FROM "history" ORDER BY "entry_id" DESC LIMIT 40"#)
+            .fetch_all(&self.pool)
             .await
     }
 
+    pub async fn client_query(&self, codename: &str) -> DBResult<Option<ClientRow>> {
+        sqlx::query_as(r#"SELECT * FROM "clients" WHERE "codename" = ?"#)
+            .bind(codename)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn client_query_all(&self) -> DBResult<Vec<ClientRow>> {
+        sqlx::query_as(r#"SELECT * FROM "clients" ORDER BY "codename""#)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    // Returns whether an existing credential was rotated, its creation and last seen time are kept
+    pub async fn client_set(
+        &self,
+        codename: &str,
+        credential: &ClientCredential,
+    ) -> DBResult<bool> {
+        let timestamp = kstool::time::get_current_second() as i64;
+        let mut tx = self.pool.begin().await?;
+        let rotated = sqlx::query(
+            r#"UPDATE "clients" SET "setting" = ?, "stored_key" = ?, "token_hash" = ?, "created" = ? WHERE "codename" = ?"#,
+        )
+        .bind(credential.setting())
+        .bind(credential.stored_key())
+        .bind(credential.token_hash())
+        .bind(timestamp)
+        .bind(codename)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if !rotated {
+            sqlx::query(
+                r#"INSERT INTO "clients" ("codename", "setting", "stored_key", "token_hash", "created") VALUES (?, ?, ?, ?, ?)"#,
+            )
+            .bind(codename)
+            .bind(credential.setting())
+            .bind(credential.stored_key())
+            .bind(credential.token_hash())
+            .bind(timestamp)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(rotated)
+    }
+
+    pub async fn client_seen(&self, codename: &str, timestamp: i64) -> DBResult<()> {
+        sqlx::query(r#"UPDATE "clients" SET "last_seen" = ? WHERE "codename" = ?"#)
+            .bind(timestamp)
+            .bind(codename)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn webhook_log(
+        &self,
+        url: &str,
+        event: &str,
+        code: &str,
+        attempt: u32,
+        status: Option<u16>,
+        error: Option<String>,
+    ) -> DBResult<()> {
+        sqlx::query(
+            r#"INSERT INTO "webhook_deliveries" ("url", "event", "code", "attempt", "status", "error", "timestamp") VALUES (?, ?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(url)
+        .bind(event)
+        .bind(code)
+        .bind(attempt)
+        .bind(status)
+        .bind(error)
+        .bind(kstool::time::get_current_second() as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn client_delete(&self, codename: &str) -> DBResult<bool> {
+        Ok(sqlx::query(r#"DELETE FROM "clients" WHERE "codename" = ?"#)
+            .bind(codename)
+            .execute(&self.pool)
+            .await?
+            .rows_affected()
+            > 0)
+    }
+
     pub async fn close(self) -> DBResult<()> {
         self.broadcast.send(current::BroadcastEvent::exit()).ok();
-        self.conn.close().await
+        self.pool.close().await;
+        Ok(())
     }
 }
 
-impl DatabaseCheckExt for Database {
+impl DatabaseCheckExt for SqliteConnection {
     fn conn_(&mut self) -> &mut sqlx::SqliteConnection {
-        &mut self.conn
+        self
     }
 }
 
 //pub type DBCallSender<T> = tokio::sync::oneshot::Sender<T>;
 //pub type DBCallback<T> = tokio::sync::oneshot::Receiver<T>;
 
-kstool_helper_generator::oneshot_helper! {
+mod event {
+    use super::DBResult;
+    use crate::types::{
+        AccessLevel, ClientCredential, ClientRow, CodeRow, Cookie, HistoryRow, User, VStats,
+    };
+
+    kstool_helper_generator::oneshot_helper! {
+    #[derive(Debug, strum::IntoStaticStr)]
+    pub enum DatabaseEvent {
+        #[ret(DBResult<bool>)]
+        UserAdd {
+            user: i64
+        },
+        #[ret(DBResult<()>)]
+        UserApprove {
+            user: i64,
+            level: AccessLevel,
+        },
+        #[ret(DBResult<()>)]
+        UserRevoke {
+            user: i64,
+        },
+        #[ret(DBResult<Option<User>>)]
+        UserQuery {
+            user: i64,
+        },
+        #[ret(DBResult<Option<CodeRow>>)]
+        CodeQuery {
+            code: String,
+        },
+        #[ret(DBResult<Vec<CodeRow>>)]
+        CodeQuerySince {
+            seq: Option<i64>,
+            timestamp: Option<i64>,
+        },
+        #[ret(DBResult<Option<bool>>)]
+        CodeReserve {
+            code: String,
+        },
+        #[ret(DBResult<()>)]
+        CodeConfirm {
+            code: String,
+            message_id: i32,
+        },
+        #[ret(DBResult<()>)]
+        CodeHold {
+            code: String,
+        },
+        #[ret(DBResult<()>)]
+        CodeRelease {
+            code: String,
+        },
+        #[ret(DBResult<bool>)]
+        CodeResent {
+            code: String,
+        },
+        #[ret(DBResult<Option<CodeRow>>)]
+        CodeFR {
+            code: String
+        },
+
+        #[ret(DBResult<Vec<Cookie>>)]
+        CookieQueryAll(bool),
+
+        #[ret(DBResult<Vec<Cookie>>)]
+        CookieQuery(i64),
+
+        #[ret(DBResult<Option<Cookie>>)]
+        CookieQueryID(String),
+
+        #[ret(DBResult<()>)]
+        CookieToggle {id: String, usable: bool},
+
+        #[ret(DBResult<bool>)]
+        CookieCheckCapacity(String, i64, usize),
+
+        #[ret(DBResult<bool>)]
+        CookieSet {user: i64, id: String, csrf: String, session: String},
+
+        #[ret(DBResult<()>)]
+        CookieUpdateTimestamp(String),
+
+        #[ret(DBResult<()>)]
+        VUpdate {v: String},
+
+        LogInsert {
+            id: String,
+            code: String,
+            error: Option<String>,
+            reward: Option<String>,
+            reporter: Option<String>,
+        },
+
+        #[ret(DBResult<Vec<HistoryRow>>)]
+        LogQuery {id: String,},
+
+        #[ret(DBResult<usize>)]
+        LogCount {code: String, error: String},
+
+        #[ret(DBResult<Vec<String>>)]
+        LogQueryCode(String),
+
+        #[ret(DBResult<Option<VStats>>)]
+        VQuery,
+
+        #[ret(DBResult<Option<ClientRow>>)]
+        ClientQuery(String),
+
+        #[ret(DBResult<Vec<ClientRow>>)]
+        ClientQueryAll,
+
+        #[ret(DBResult<bool>)]
+        ClientIssue {codename: String, credential: ClientCredential},
+
+        #[ret(DBResult<bool>)]
+        ClientRevoke(String),
+
+        ClientSeen {codename: String, timestamp: i64},
+
+        WebhookLog {
+            url: String,
+            event: &'static str,
+            code: String,
+            attempt: u32,
+            status: Option<u16>,
+            error: Option<String>,
+        },
+
+        Terminate,
+    }
+    }
+
+    impl DatabaseEvent {
+        // Pure queries, safe to run out of order with each other
+        pub(super) fn is_read(&self) -> bool {
+            matches!(
+                self,
+                Self::UserQuery { .. }
+                    | Self::CodeQuery { .. }
+                    | Self::CodeQuerySince { .. }
+                    | Self::CookieQueryAll(..)
+                    | Self::CookieQuery(..)
+                    | Self::CookieQueryID(..)
+                    | Self::CookieCheckCapacity(..)
+                    | Self::VQuery(..)
+                    | Self::LogQuery { .. }
+                    | Self::LogCount { .. }
+                    | Self::LogQueryCode(..)
+                    | Self::ClientQuery(..)
+                    | Self::ClientQueryAll(..)
+            )
+        }
+    }
+
+    impl DatabaseHelper {
+        pub fn queue_depth(&self) -> usize {
+            self.sender.max_capacity() - self.sender.capacity()
+        }
+    }
+}
+
+use event::{DatabaseEvent, DatabaseEventReceiver};
+
 #[derive(Debug)]
-pub enum DatabaseEvent {
-    #[ret(bool)]
-    UserAdd {
-        user: i64
-    },
-    #[ret(())]
-    UserApprove {
-        user: i64,
-        level: AccessLevel,
-    },
-    #[ret(())]
-    UserRevoke {
-        user: i64,
-    },
-    #[ret(Option<User>)]
-    UserQuery {
-        user: i64,
-    },
-    #[ret(Option<CodeRow>)]
-    CodeQuery {
-        code: String,
-    },
-    #[ret(())]
-    CodeAdd {
-        code: String,
-        message_id: i32,
-    },
-    #[ret(())]
-    CodeResent {
-        code: String,
-    },
-    #[ret(Option<CodeRow>)]
-    CodeFR {
-        code: String
-    },
+pub enum DatabaseError {
+    // Actor is gone, request was never served
+    Closed,
+    // No reply within deadline, request may still be applied later
+    Timeout,
+    Constraint(String),
+    Sqlx(sqlx::Error),
+}
 
-    #[ret(Vec<Cookie>)]
-    CookieQueryAll(bool),
+impl std::fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Closed => write!(f, "database is not running"),
+            Self::Timeout => write!(f, "database request timed out"),
+            Self::Constraint(message) => write!(f, "constraint violated: {message}"),
+            Self::Sqlx(e) => write!(f, "query failed: {e}"),
+        }
+    }
+}
 
-    #[ret(Vec<Cookie>)]
-    CookieQuery(i64),
+impl std::error::Error for DatabaseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Sqlx(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
-    #[ret(Option<Cookie>)]
-    CookieQueryID(String),
+impl From<sqlx::Error> for DatabaseError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::Database(error)
+                if matches!(
+                    error.kind(),
+                    ErrorKind::UniqueViolation
+                        | ErrorKind::ForeignKeyViolation
+                        | ErrorKind::NotNullViolation
+                        | ErrorKind::CheckViolation
+                ) =>
+            {
+                Self::Constraint(error.message().to_string())
+            }
+            _ => Self::Sqlx(e),
+        }
+    }
+}
 
-    #[ret(())]
-    CookieToggle {id: String, usable: bool},
+pub type DatabaseResult<T> = Result<T, DatabaseError>;
 
-    #[ret(bool)]
-    CookieCheckCapacity(String, i64, usize),
+#[derive(Clone, Debug)]
+pub struct DatabaseHelper {
+    inner: event::DatabaseHelper,
+    deadline: Duration,
+}
 
-    #[ret(bool)]
-    CookieSet {user: i64, id: String, csrf: String, session: String},
+// Wrap generated helper methods with deadline and typed error
+macro_rules! request {
+    ($($name:ident($($arg:ident: $ty:ty),*) -> $ret:ty;)*) => {
+        $(
+            pub async fn $name(&self, $($arg: $ty),*) -> DatabaseResult<$ret> {
+                self.request(self.inner.$name($($arg),*)).await
+            }
+        )*
+    };
+}
 
-    #[ret(())]
-    CookieUpdateTimestamp(String),
+macro_rules! notify {
+    ($($name:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            pub async fn $name(&self, $($arg: $ty),*) -> DatabaseResult<()> {
+                self.notify(self.inner.$name($($arg),*)).await
+            }
+        )*
+    };
+}
 
-    #[ret(())]
-    VUpdate {v: String},
+impl DatabaseHelper {
+    fn new(size: usize, deadline: Duration) -> (Self, DatabaseEventReceiver) {
+        let (inner, receiver) = event::DatabaseHelper::new(size);
+        (Self { inner, deadline }, receiver)
+    }
 
-    LogInsert {
-        id: String,
-        code: String,
-        error: Option<String>
-    },
+    pub fn queue_depth(&self) -> usize {
+        self.inner.queue_depth()
+    }
 
-    #[ret(Vec<HistoryRow>)]
-    LogQuery {id: String,},
+    async fn request<T>(
+        &self,
+        request: impl Future<Output = Option<DBResult<T>>>,
+    ) -> DatabaseResult<T> {
+        tokio::time::timeout(self.deadline, request)
+            .await
+            .map_err(|_| DatabaseError::Timeout)?
+            .ok_or(DatabaseError::Closed)?
+            .map_err(DatabaseError::from)
+    }
 
-    #[ret(Option<VStats>)]
-    VQuery,
+    async fn notify(&self, request: impl Future<Output = Option<()>>) -> DatabaseResult<()> {
+        tokio::time::timeout(self.deadline, request)
+            .await
+            .map_err(|_| DatabaseError::Timeout)?
+            .ok_or(DatabaseError::Closed)
+    }
 
-    Terminate,
-}
+    request! {
+        user_add(user: i64) -> bool;
+        user_approve(user: i64, level: AccessLevel) -> ();
+        user_revoke(user: i64) -> ();
+        user_query(user: i64) -> Option<User>;
+        code_query(code: String) -> Option<CodeRow>;
+        code_query_since(seq: Option<i64>, timestamp: Option<i64>) -> Vec<CodeRow>;
+        code_reserve(code: String) -> Option<bool>;
+        code_confirm(code: String, message_id: i32) -> ();
+        code_hold(code: String) -> ();
+        code_release(code: String) -> ();
+        code_resent(code: String) -> bool;
+        code_fr(code: String) -> Option<CodeRow>;
+        cookie_query_all(enabled_only: bool) -> Vec<Cookie>;
+        cookie_query(user: i64) -> Vec<Cookie>;
+        cookie_query_id(id: String) -> Option<Cookie>;
+        cookie_toggle(id: String, usable: bool) -> ();
+        cookie_check_capacity(id: String, user: i64, capacity: usize) -> bool;
+        cookie_set(user: i64, id: String, csrf: String, session: String) -> bool;
+        cookie_update_timestamp(id: String) -> ();
+        v_update(v: String) -> ();
+        log_query(id: String) -> Vec<HistoryRow>;
+        log_count(code: String, error: String) -> usize;
+        log_query_code(code: String) -> Vec<String>;
+        v_query() -> Option<VStats>;
+        client_query(codename: String) -> Option<ClientRow>;
+        client_query_all() -> Vec<ClientRow>;
+        client_issue(codename: String, credential: ClientCredential) -> bool;
+        client_revoke(codename: String) -> bool;
+    }
+
+    notify! {
+        log_insert(
+            id: String,
+            code: String,
+            error: Option<String>,
+            reward: Option<String>,
+            reporter: Option<String>
+        );
+        client_seen(codename: String, timestamp: i64);
+        webhook_log(
+            url: String,
+            event: &'static str,
+            code: String,
+            attempt: u32,
+            status: Option<u16>,
+            error: Option<String>
+        );
+        terminate();
+    }
 }
 
 pub struct DatabaseHandle {
@@ -493,6 +1258,7 @@ pub struct DatabaseHandle {
 impl DatabaseHandle {
     pub async fn connect(
         file: &str,
+        deadline: Duration,
     ) -> anyhow::Result<(
         Self,
         DatabaseHelper,
@@ -501,7 +1267,7 @@ impl DatabaseHandle {
         let (s, r) = broadcast::channel(32);
         let mut database = Database::connect(file, s).await?;
         database.init().await?;
-        let (sender, receiver) = DatabaseHelper::new(2048);
+        let (sender, receiver) = DatabaseHelper::new(2048, deadline);
         Ok((
             Self {
                 handle: tokio::spawn(Self::run(database, receiver)),
@@ -511,104 +1277,148 @@ impl DatabaseHandle {
         ))
     }
 
-    async fn handle_event(database: &mut Database, event: DatabaseEvent) -> DBResult<()> {
+    async fn handle_event(database: &Database, event: DatabaseEvent) {
         match event {
             DatabaseEvent::UserAdd {
                 user,
                 __private_sender,
             } => {
-                let u = database.query_user(user).await?;
-                if u.is_none() {
-                    database.insert_user(user, AccessLevel::NoAccess).await?;
-                    info!("Add user {} to database", user);
-                }
-                __private_sender.send(u.is_none()).ok();
+                let result = async {
+                    let u = database.query_user(user).await?;
+                    if u.is_none() {
+                        database.insert_user(user, AccessLevel::NoAccess).await?;
+                        info!("Add user {} to database", user);
+                    }
+                    Ok(u.is_none())
+                };
+                reply(__private_sender, result.await);
             }
             DatabaseEvent::UserApprove {
                 user,
                 level,
                 __private_sender,
             } => {
-                database.set_authorized_status(user, level).await?;
-                info!("Approve user {}", user);
-                __private_sender.send(()).ok();
+                let result = database.set_authorized_status(user, level).await;
+                if result.is_ok() {
+                    info!("Approve user {}", user);
+                }
+                reply(__private_sender, result);
             }
             DatabaseEvent::UserRevoke {
                 user,
                 __private_sender,
             } => {
-                database
-                    .set_authorized_status(user, AccessLevel::NoAccess)
-                    .await?;
-                __private_sender.send(()).ok();
+                reply(
+                    __private_sender,
+                    database
+                        .set_authorized_status(user, AccessLevel::NoAccess)
+                        .await,
+                );
             }
 
-            DatabaseEvent::CodeAdd {
+            DatabaseEvent::CodeReserve {
+                code,
+                __private_sender,
+            } => {
+                reply(__private_sender, database.reserve_code(&code).await);
+            }
+            DatabaseEvent::CodeConfirm {
                 code,
-
                 message_id,
                 __private_sender,
             } => {
-                database.insert_code(&code, message_id).await?;
-                __private_sender.send(()).ok();
+                reply(
+                    __private_sender,
+                    database.confirm_code(&code, message_id).await,
+                );
+            }
+            DatabaseEvent::CodeHold {
+                code,
+                __private_sender,
+            } => {
+                reply(__private_sender, database.hold_code(&code).await);
+            }
+            DatabaseEvent::CodeRelease {
+                code,
+                __private_sender,
+            } => {
+                reply(__private_sender, database.release_code(&code).await);
             }
             DatabaseEvent::CodeFR {
                 code,
                 __private_sender,
             } => {
-                database.set_code_fr(&code, true).await?;
-                let code = database.query_code(&code).await?;
-                __private_sender.send(code).ok();
+                let result = async {
+                    let changed = database.set_code_fr(&code, true).await?;
+                    let code = database.query_code(&code).await?;
+                    if changed && let Some(row) = &code {
+                        METRICS.code_fr();
+                        database
+                            .broadcast
+                            .send(BroadcastEvent::fr(row.clone().into()))
+                            .ok()
+                            .tap_none(|| error!("Unable send broadcast"));
+                    }
+                    Ok(code)
+                };
+                reply(__private_sender, result.await);
             }
             DatabaseEvent::CodeQuery {
                 code,
                 __private_sender,
             } => {
-                __private_sender
-                    .send(database.query_code(&code).await?)
-                    .ok();
+                reply(__private_sender, database.query_code(&code).await);
+            }
+            DatabaseEvent::CodeQuerySince {
+                seq,
+                timestamp,
+                __private_sender,
+            } => {
+                reply(
+                    __private_sender,
+                    database.query_code_since(seq, timestamp).await,
+                );
             }
             DatabaseEvent::Terminate => unreachable!(),
             DatabaseEvent::UserQuery {
                 user,
                 __private_sender,
             } => {
-                __private_sender.send(database.query_user(user).await?).ok();
+                reply(__private_sender, database.query_user(user).await);
             }
 
             DatabaseEvent::CookieQuery(id, sender) => {
-                sender.send(database.cookie_query_user(id).await?).ok();
+                reply(sender, database.cookie_query_user(id).await);
             }
             DatabaseEvent::CookieQueryID(id, sender) => {
-                sender.send(database.cookie_query(&id).await?).ok();
+                reply(sender, database.cookie_query(&id).await);
             }
             DatabaseEvent::CookieQueryAll(enabled_only, sender) => {
-                sender
-                    .send(if enabled_only {
+                reply(
+                    sender,
+                    if enabled_only {
                         database.cookie_query_all_enabled().await
                     } else {
                         database.cookie_query_all().await
-                    }?)
-                    .ok();
+                    },
+                );
             }
             DatabaseEvent::VUpdate {
                 v,
                 __private_sender,
             } => {
-                __private_sender.send(database.v_update(v).await?).ok();
+                reply(__private_sender, database.v_update(v).await);
             }
 
             DatabaseEvent::VQuery(sender) => {
-                sender.send(database.v_query().await?).ok();
+                reply(sender, database.v_query().await);
             }
             DatabaseEvent::CookieToggle {
                 id,
                 usable,
                 __private_sender,
             } => {
-                __private_sender
-                    .send(database.cookie_usable(&id, usable).await?)
-                    .ok();
+                reply(__private_sender, database.cookie_usable(&id, usable).await);
             }
             DatabaseEvent::CookieSet {
                 user,
@@ -617,72 +1427,560 @@ impl DatabaseHandle {
                 session,
                 __private_sender,
             } => {
-                __private_sender
-                    .send(database.cookie_set(user, &csrf, &session, &id).await?)
-                    .ok();
+                reply(
+                    __private_sender,
+                    database.cookie_set(user, &csrf, &session, &id).await,
+                );
             }
             DatabaseEvent::CookieUpdateTimestamp(id, sender) => {
-                sender
-                    .send(database.cookie_update_timestamp(&id).await?)
-                    .ok();
+                reply(sender, database.cookie_update_timestamp(&id).await);
             }
-            DatabaseEvent::LogInsert { id, code, error } => {
-                database.log_add(&id, &code, error).await?;
+            DatabaseEvent::LogInsert {
+                id,
+                code,
+                error,
+                reward,
+                reporter,
+            } => {
+                log_error(database.log_add(&id, &code, error, reward, reporter).await).ok();
             }
             DatabaseEvent::LogQuery {
                 id,
                 __private_sender,
             } => {
-                __private_sender
-                    .send(if id.is_empty() {
+                reply(
+                    __private_sender,
+                    if id.is_empty() {
                         database.log_query_all().await
                     } else {
                         database.log_query(&id).await
-                    }?)
-                    .ok();
+                    },
+                );
+            }
+            DatabaseEvent::LogCount {
+                code,
+                error,
+                __private_sender,
+            } => {
+                reply(__private_sender, database.log_count(&code, &error).await);
+            }
+            DatabaseEvent::LogQueryCode(code, sender) => {
+                reply(sender, database.log_query_code(&code).await);
             }
             DatabaseEvent::CodeResent {
                 code,
                 __private_sender,
             } => {
-                database.broadcast.send(BroadcastEvent::NewCode(code)).ok();
-                __private_sender.send(()).ok();
+                let result = database.query_code(&code).await.map(|row| {
+                    let found = row.is_some();
+                    if let Some(row) = row {
+                        database
+                            .broadcast
+                            .send(BroadcastEvent::resend(row.into()))
+                            .ok();
+                    }
+                    found
+                });
+                reply(__private_sender, result);
+            }
+            DatabaseEvent::ClientQuery(codename, sender) => {
+                reply(sender, database.client_query(&codename).await);
+            }
+            DatabaseEvent::ClientQueryAll(sender) => {
+                reply(sender, database.client_query_all().await);
+            }
+            DatabaseEvent::ClientIssue {
+                codename,
+                credential,
+                __private_sender,
+            } => {
+                reply(
+                    __private_sender,
+                    database.client_set(&codename, &credential).await,
+                );
+            }
+            DatabaseEvent::ClientRevoke(codename, sender) => {
+                reply(sender, database.client_delete(&codename).await);
+            }
+            DatabaseEvent::WebhookLog {
+                url,
+                event,
+                code,
+                attempt,
+                status,
+                error,
+            } => {
+                log_error(
+                    database
+                        .webhook_log(&url, event, &code, attempt, status, error)
+                        .await,
+                )
+                .ok();
+            }
+            DatabaseEvent::ClientSeen {
+                codename,
+                timestamp,
+            } => {
+                log_error(database.client_seen(&codename, timestamp).await).ok();
             }
             DatabaseEvent::CookieCheckCapacity(codename, id, capacity, sender) => {
-                sender
-                    .send(
-                        database.cookie_query(&codename).await?.is_some()
-                            || database.cookie_query_user(id).await?.len() <= capacity,
-                    )
-                    .ok();
+                let result = async {
+                    Ok(database.cookie_query(&codename).await?.is_some()
+                        || database.cookie_query_user(id).await?.len() <= capacity)
+                };
+                reply(sender, result.await);
             }
         }
-        Ok(())
     }
 
-    async fn run(mut database: Database, mut receiver: DatabaseEventReceiver) -> DBResult<()> {
+    async fn process(database: &Database, event: DatabaseEvent) {
+        let name: &'static str = (&event).into();
+        let start = std::time::Instant::now();
+        Self::handle_event(database, event).await;
+        METRICS.database_event(name, start.elapsed().as_secs_f64());
+    }
+
+    async fn run(database: Database, mut receiver: DatabaseEventReceiver) -> DBResult<()> {
         while let Some(event) = receiver.recv().await {
             if let DatabaseEvent::Terminate = event {
                 break;
             }
-            Self::handle_event(&mut database, event)
-                .await
-                .inspect_err(|e| error!("Sqlite error: {e:?}"))?;
+            // Reads go to the pool so they don't queue behind each other, writes stay in order
+            if event.is_read() {
+                let database = database.clone();
+                tokio::spawn(async move { Self::process(&database, event).await });
+                continue;
+            }
+            Self::process(&database, event).await;
         }
         database.close().await?;
         Ok(())
     }
 
+    // Apply migrations without starting the actor, `dry_run` applies them to a copy and leaves the file alone
+    pub async fn migrate(file: &str, to: Option<u32>, dry_run: bool) -> anyhow::Result<()> {
+        // Reject a bad target before anything is opened, so no file is created for it
+        let latest: u32 = current::VERSION.parse()?;
+        if let Some(to) = to
+            && to > latest
+        {
+            return Err(anyhow!("Unknown version {to}, latest is {latest}"));
+        }
+        let exists = std::path::Path::new(file).exists();
+        let fresh = !exists || !Self::versioned(file).await?;
+        if fresh && to.is_some_and(|to| to != latest) {
+            return Err(anyhow!("New database can only be created at v{latest}"));
+        }
+        if dry_run {
+            if !exists {
+                println!("{file} does not exist, would be created at v{latest}");
+                return Ok(());
+            }
+            if fresh {
+                println!("{file} would be created at v{latest}");
+                return Ok(());
+            }
+            return Self::migrate_dry_run(file, to).await;
+        }
+
+        let (s, _r) = broadcast::channel(1);
+        let database = Database::connect(file, s).await?;
+        let mut conn = database.pool.acquire().await?;
+
+        if fresh {
+            conn.create_db().await?;
+            conn.insert_database_version().await?;
+            println!("{file} is created at v{latest}");
+        } else {
+            let from = Database::version(&mut conn).await?;
+            let applied = Database::migrate(&mut conn, to).await?;
+            Self::print_migrated(file, from, &applied, false);
+        }
+        drop(conn);
+        database.close().await?;
+        Ok(())
+    }
+
+    // Existing file without the version table is treated as a new database
+    async fn versioned(file: &str) -> anyhow::Result<bool> {
+        let mut conn = SqliteConnectOptions::new()
+            .filename(file)
+            .read_only(true)
+            .connect()
+            .await?;
+        let versioned = conn.check_database_table().await?;
+        conn.close().await?;
+        Ok(versioned)
+    }
+
+    // Database is opened read only with its journal mode untouched, migrations run on a throwaway copy
+    async fn migrate_dry_run(file: &str, to: Option<u32>) -> anyhow::Result<()> {
+        let mut source = SqliteConnectOptions::new()
+            .filename(file)
+            .read_only(true)
+            .connect()
+            .await?;
+        let from = Database::version(&mut source).await?;
+
+        let copy = ScratchFile(
+            std::env::temp_dir().join(format!("code-forwarder-dry-run-{}.db", std::process::id())),
+        );
+        std::fs::remove_file(&copy.0).ok();
+        sqlx::query("VACUUM INTO ?")
+            .bind(copy.0.to_string_lossy())
+            .execute(&mut source)
+            .await?;
+        source.close().await?;
+
+        let mut conn = SqliteConnectOptions::new()
+            .filename(&copy.0)
+            .connect()
+            .await?;
+        let applied = Database::migrate(&mut conn, to).await;
+        conn.close().await?;
+        Self::print_migrated(file, from, &applied?, true);
+        Ok(())
+    }
+
+    fn print_migrated(file: &str, from: u32, applied: &[u32], dry_run: bool) {
+        match applied.last() {
+            Some(version) => println!(
+                "{file} {} migrated from v{from} to v{version} ({})",
+                if dry_run { "can be" } else { "is" },
+                applied
+                    .iter()
+                    .map(|version| format!("v{version}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            None => println!("{file} is at v{from}, nothing to migrate"),
+        }
+    }
+
     pub async fn wait(self) -> anyhow::Result<()> {
         Ok(self.handle.await??)
     }
 }
 
 pub type DBResult<T> = sqlx::Result<T>;
+
+// Removed however the dry run ends
+struct ScratchFile(std::path::PathBuf);
+
+impl Drop for ScratchFile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).ok();
+    }
+}
+
+fn log_error<T>(result: DBResult<T>) -> DBResult<T> {
+    result.inspect_err(|e| error!("Sqlite error: {e:?}"))
+}
+
+// Failed query goes back to its caller, the actor keeps serving others
+fn reply<T>(sender: tokio::sync::oneshot::Sender<DBResult<T>>, result: DBResult<T>) {
+    sender.send(log_error(result)).ok();
+}
+
 use tap::TapOptional;
 use tokio::sync::broadcast;
-pub use v2 as current;
+pub use v9 as current;
 
-use crate::types::{AccessLevel, CodeRow, Cookie, HistoryRow, MetaRow, User, VStats};
+use crate::metrics::METRICS;
+use crate::types::{
+    AccessLevel, ClientCredential, ClientRow, CodeRow, Cookie, HistoryRow, MetaRow, User, VStats,
+};
 
 pub use current::BroadcastEvent;
+
+#[cfg(test)]
+pub mod testing {
+    use std::time::Duration;
+
+    use tokio::sync::broadcast;
+
+    use super::{BroadcastEvent, DatabaseHandle, DatabaseHelper};
+
+    // Database in its own temporary directory, removed with its -wal and -shm files even if a test panics
+    pub struct TestDatabase {
+        pub handle: DatabaseHandle,
+        pub database: DatabaseHelper,
+        pub broadcast: broadcast::Receiver<BroadcastEvent>,
+        file: String,
+        _dir: tempfile::TempDir,
+    }
+
+    impl TestDatabase {
+        pub async fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let file = dir.path().join("test.db").to_str().unwrap().to_string();
+            let (handle, database, broadcast) =
+                DatabaseHandle::connect(&file, Duration::from_secs(5))
+                    .await
+                    .unwrap();
+            Self {
+                handle,
+                database,
+                broadcast,
+                file,
+                _dir: dir,
+            }
+        }
+
+        pub fn file(&self) -> &str {
+            &self.file
+        }
+
+        // Stop the actor before its directory is removed
+        pub async fn close(self) {
+            self.database.terminate().await.unwrap();
+            self.handle.wait().await.unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::Connection as _;
+
+    use super::{
+        BroadcastEvent, ClientCredential, ClientRow, Database, DatabaseError, current,
+        testing::TestDatabase,
+    };
+
+    #[test]
+    fn test_plan() {
+        let versions = |from, to| {
+            Database::plan(from, to)
+                .unwrap()
+                .iter()
+                .map(|migration| migration.version)
+                .collect::<Vec<_>>()
+        };
+        let latest: u32 = current::VERSION.parse().unwrap();
+        assert_eq!(versions(1, None).first(), Some(&2));
+        assert_eq!(versions(1, None).last(), Some(&latest));
+        assert_eq!(versions(3, Some(5)), [4, 5]);
+        assert!(versions(latest, None).is_empty());
+        assert!(Database::plan(5, Some(3)).is_err());
+        assert!(Database::plan(1, Some(latest + 1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_actor_survives_error() {
+        let test = TestDatabase::new().await;
+        let database = test.database.clone();
+
+        assert_eq!(database.code_reserve("abcde".into()).await.unwrap(), None);
+        database.code_confirm("abcde".into(), 1).await.unwrap();
+        assert_eq!(database.code_reserve("fghij".into()).await.unwrap(), None);
+        // Another code posted as the same message violates UNIQUE constraint
+        assert!(matches!(
+            database.code_confirm("fghij".into(), 1).await,
+            Err(DatabaseError::Constraint(_))
+        ));
+        assert!(matches!(
+            database.code_query("abcde".into()).await,
+            Ok(Some(_))
+        ));
+
+        test.close().await;
+        assert!(matches!(
+            database.code_query("abcde".into()).await,
+            Err(DatabaseError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_log_count_by_reporter() {
+        let test = TestDatabase::new().await;
+        let database = &test.database;
+
+        // One client reporting under made up codenames counts once
+        for (id, reporter) in [("a1", "alice"), ("a2", "alice"), ("b1", "bob")] {
+            database
+                .log_insert(
+                    id.into(),
+                    "abcde".into(),
+                    Some("FR".into()),
+                    None,
+                    Some(reporter.into()),
+                )
+                .await
+                .unwrap();
+        }
+        database
+            .log_insert("c1".into(), "abcde".into(), Some("FR".into()), None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .log_count("abcde".into(), "FR".into())
+                .await
+                .unwrap(),
+            3
+        );
+
+        test.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_reserve_code() {
+        let test = TestDatabase::new().await;
+        let database = &test.database;
+
+        // Only one of concurrent submitters gets the reservation
+        let (first, second) = tokio::join!(
+            database.code_reserve("abcde".into()),
+            database.code_reserve("abcde".into())
+        );
+        let mut results = [first.unwrap(), second.unwrap()];
+        results.sort();
+        assert_eq!(results, [None, Some(false)]);
+        assert!(matches!(
+            database.code_query("abcde".into()).await,
+            Ok(None)
+        ));
+
+        // Failed post gives the code back
+        database.code_release("abcde".into()).await.unwrap();
+        assert_eq!(database.code_reserve("abcde".into()).await.unwrap(), None);
+        database.code_confirm("abcde".into(), 1).await.unwrap();
+        assert!(matches!(
+            database.code_query("abcde".into()).await,
+            Ok(Some(row)) if row.message_id() == 1
+        ));
+        assert_eq!(
+            database.code_reserve("abcde".into()).await.unwrap(),
+            Some(false)
+        );
+
+        // Reservation nobody released expires
+        let mut conn = sqlx::SqliteConnection::connect(test.file()).await.unwrap();
+        sqlx::query(r#"INSERT INTO "code_reservations" VALUES ('fghij', 0), ('klmno', 0)"#)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        conn.close().await.unwrap();
+        // Unless it is held by a record still being retried
+        database.code_hold("klmno".into()).await.unwrap();
+        assert_eq!(database.code_reserve("fghij".into()).await.unwrap(), None);
+        assert_eq!(
+            database.code_reserve("klmno".into()).await.unwrap(),
+            Some(false)
+        );
+
+        test.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_confirm_order() {
+        let mut test = TestDatabase::new().await;
+        let database = test.database.clone();
+
+        let since = kstool::time::get_current_second() as i64;
+        for code in ["aaaaa", "bbbbb"] {
+            assert_eq!(database.code_reserve(code.into()).await.unwrap(), None);
+        }
+        // Later reservation posted first is published first
+        database.code_confirm("bbbbb".into(), 2).await.unwrap();
+        database.code_confirm("aaaaa".into(), 1).await.unwrap();
+        // Confirm repeated after a timeout publishes nothing again
+        database.code_confirm("aaaaa".into(), 1).await.unwrap();
+
+        let rows = database.code_query_since(None, None).await.unwrap();
+        let codes = rows.iter().map(|row| row.code()).collect::<Vec<_>>();
+        assert_eq!(codes, ["bbbbb", "aaaaa"]);
+        // Client which has seen bbbbb still gets aaaaa on resume
+        let replay = database
+            .code_query_since(Some(rows[0].seq()), None)
+            .await
+            .unwrap();
+        assert_eq!(replay.len(), 1);
+        assert_eq!(replay[0].code(), "aaaaa");
+        // Codes published within the resumed second are not lost
+        assert_eq!(
+            database
+                .code_query_since(None, Some(since))
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+
+        for expected in ["bbbbb", "aaaaa"] {
+            assert!(matches!(
+                test.broadcast.recv().await,
+                Ok(BroadcastEvent::NewCode(code)) if code.code() == expected
+            ));
+        }
+        assert!(test.broadcast.try_recv().is_err());
+
+        test.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_client_rotate() {
+        let test = TestDatabase::new().await;
+        let database = &test.database;
+
+        assert!(
+            !database
+                .client_issue("alice".into(), ClientRow::issue().unwrap().2)
+                .await
+                .unwrap()
+        );
+        database.client_seen("alice".into(), 1).await.unwrap();
+        assert!(
+            database
+                .client_issue("alice".into(), ClientRow::issue().unwrap().2)
+                .await
+                .unwrap()
+        );
+        let client = database
+            .client_query("alice".into())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(client.to_string().contains("last seen"));
+
+        test.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_migrate_credentials() {
+        use argon2::{PasswordHasher as _, password_hash::SaltString};
+
+        let mut conn = sqlx::SqliteConnection::connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::query(
+            r#"CREATE TABLE "clients" ("codename" TEXT NOT NULL, "hash" TEXT NOT NULL, "created" INTEGER NOT NULL, "last_seen" INTEGER, PRIMARY KEY("codename"))"#,
+        )
+        .execute(&mut conn)
+        .await
+        .unwrap();
+        let salt = SaltString::encode_b64(&[0; 16]).unwrap();
+        let hash = argon2::Argon2::default()
+            .hash_password(b"secret", &salt)
+            .unwrap()
+            .to_string();
+        sqlx::query(r#"INSERT INTO "clients" VALUES ('alice', ?, 1, 2)"#)
+            .bind(&hash)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        super::v9::migration_v8(&mut conn).await.unwrap();
+        let client: ClientRow = sqlx::query_as(r#"SELECT * FROM "clients""#)
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+        // Issued token keeps working, argon2 output is gone
+        let (credential, token) = ClientCredential::from_hash(&hash).unwrap();
+        assert!(client.verify_token(&token));
+        assert_eq!(client.setting(), credential.setting());
+        assert!(hash.starts_with(client.setting()) && hash != client.setting());
+        assert!(client.to_string().contains("last seen"));
+    }
+}
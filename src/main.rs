@@ -2,35 +2,61 @@ use clap::arg;
 use config::Config;
 use database::DatabaseHandle;
 use log::error;
+use platform::NecessaryArg;
+use teloxide::types::ChatId;
 
 mod config;
 mod database;
+mod metrics;
 mod platform;
 mod private;
 mod types;
 pub mod web;
-use std::io::Write;
+mod webhook;
+use std::{io::Write, sync::Arc};
 
 async fn async_main(config: String) -> anyhow::Result<()> {
     let config = Config::load(&config)
         .await
         .inspect_err(|e| error!("Load configure error: {e:?}"))?;
 
-    let (database, operator, broadcast) = DatabaseHandle::connect(config.database())
-        .await
-        .inspect_err(|e| error!("Load database error: {e:?}"))?;
+    let (database, operator, broadcast) =
+        DatabaseHandle::connect(config.database(), config.database_timeout())
+            .await
+            .inspect_err(|e| error!("Load database error: {e:?}"))?;
 
     let totp = config.get_totp()?;
 
-    let web = tokio::spawn(web::route(config.clone(), broadcast.resubscribe()));
-
     let bot = platform::bot(&config)?;
 
+    let arg = Arc::new(NecessaryArg::new(
+        operator.clone(),
+        config.admin().iter().map(|u| ChatId(*u)).collect(),
+        config.platform().target(),
+        totp,
+    ));
+
+    let web = config.web().enabled().then(|| {
+        tokio::spawn(web::route(
+            config.clone(),
+            bot.clone(),
+            arg.clone(),
+            broadcast.resubscribe(),
+        ))
+    });
+
+    let webhooks = if config.webhooks().is_empty() {
+        None
+    } else {
+        let webhooks = webhook::Webhooks::new(config.webhooks().to_vec(), operator.clone())?;
+        Some(tokio::spawn(webhooks.run(broadcast.resubscribe())))
+    };
+
     let code_master = private::CodeStaff::start(bot.clone(), operator.clone(), broadcast);
 
-    platform::bot_run(bot, config, operator.clone().into(), totp).await?;
+    platform::bot_run(bot, arg).await?;
 
-    operator.terminate().await;
+    operator.terminate().await.ok();
 
     code_master.wait().await?;
 
@@ -39,10 +65,22 @@ async fn async_main(config: String) -> anyhow::Result<()> {
         .await
         .inspect_err(|e| error!("Database error: {e:?}"))?;
 
-    web.await??;
+    if let Some(web) = web {
+        web.await??;
+    }
+    if let Some(webhooks) = webhooks {
+        webhooks.await?;
+    }
     Ok(())
 }
 
+async fn async_migrate(config: String, to: Option<u32>, dry_run: bool) -> anyhow::Result<()> {
+    let config = Config::load(&config)
+        .await
+        .inspect_err(|e| error!("Load configure error: {e:?}"))?;
+    DatabaseHandle::migrate(config.database(), to, dry_run).await
+}
+
 fn init_log(systemd: bool) {
     let mut builder = env_logger::Builder::from_default_env();
     builder
@@ -63,15 +101,30 @@ fn main() -> anyhow::Result<()> {
             arg!([CONFIG] "Configure file").default_value("config.toml"),
             arg!(--systemd "Disable time output in log"),
         ])
+        .subcommand(
+            clap::Command::new("migrate")
+                .about("Upgrade database schema and exit")
+                .args(&[
+                    arg!(--"dry-run" "Check pending migrations without applying them"),
+                    arg!(--to <VERSION> "Stop at this schema version instead of latest")
+                        .value_parser(clap::value_parser!(u32)),
+                ]),
+        )
         .get_matches();
 
     init_log(matches.get_flag("systemd"));
 
-    tokio::runtime::Builder::new_multi_thread()
+    let config = matches.get_one::<String>("CONFIG").unwrap().to_string();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
-        .unwrap()
-        .block_on(async_main(
-            matches.get_one::<String>("CONFIG").unwrap().to_string(),
-        ))
+        .unwrap();
+    match matches.subcommand() {
+        Some(("migrate", matches)) => runtime.block_on(async_migrate(
+            config,
+            matches.get_one::<u32>("to").copied(),
+            matches.get_flag("dry-run"),
+        )),
+        _ => runtime.block_on(async_main(config)),
+    }
 }
@@ -0,0 +1,160 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder, core::Collector,
+};
+
+use crate::types::SubmitStatus;
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    codes_received: IntCounter,
+    codes_forwarded: IntCounter,
+    codes_duplicated: IntCounter,
+    codes_fr: IntCounter,
+    ws_connected: IntGauge,
+    ws_authenticated: IntGauge,
+    database_queue: IntGauge,
+    database_latency: HistogramVec,
+    telegram_errors: IntCounter,
+    history_errors: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("passcode".to_string()), None).unwrap();
+
+        Self {
+            codes_received: register(
+                &registry,
+                IntCounter::new("codes_received_total", "Passcodes submitted"),
+            ),
+            codes_forwarded: register(
+                &registry,
+                IntCounter::new("codes_forwarded_total", "Passcodes forwarded to target"),
+            ),
+            codes_duplicated: register(
+                &registry,
+                IntCounter::new("codes_duplicated_total", "Passcodes submitted again"),
+            ),
+            codes_fr: register(
+                &registry,
+                IntCounter::new("codes_fr_total", "Passcodes marked as FR"),
+            ),
+            ws_connected: register(
+                &registry,
+                IntGauge::new(
+                    "ws_connected_clients",
+                    "Connected WebSocket and event stream clients",
+                ),
+            ),
+            ws_authenticated: register(
+                &registry,
+                IntGauge::new(
+                    "ws_authenticated_clients",
+                    "Authenticated WebSocket and event stream clients",
+                ),
+            ),
+            database_queue: register(
+                &registry,
+                IntGauge::new("database_queue_depth", "Pending database actor events"),
+            ),
+            database_latency: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "database_event_duration_seconds",
+                        "Database actor event handling latency",
+                    ),
+                    &["event"],
+                ),
+            ),
+            telegram_errors: register(
+                &registry,
+                IntCounter::new("telegram_errors_total", "Telegram API request errors"),
+            ),
+            history_errors: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("history_errors_total", "Redeem errors in history"),
+                    &["codename"],
+                ),
+            ),
+            registry,
+        }
+    }
+
+    pub fn submit(&self, status: SubmitStatus) {
+        self.codes_received.inc();
+        match status {
+            SubmitStatus::Accepted => self.codes_forwarded.inc(),
+            SubmitStatus::Duplicate | SubmitStatus::AlreadyFR => self.codes_duplicated.inc(),
+            SubmitStatus::Rejected | SubmitStatus::Failed => {}
+        }
+    }
+
+    pub fn code_fr(&self) {
+        self.codes_fr.inc();
+    }
+
+    pub fn ws_connected(&self) -> &IntGauge {
+        &self.ws_connected
+    }
+
+    pub fn ws_authenticated(&self) -> &IntGauge {
+        &self.ws_authenticated
+    }
+
+    pub fn database_event(&self, event: &str, seconds: f64) {
+        self.database_latency
+            .with_label_values(&[event])
+            .observe(seconds);
+    }
+
+    pub fn telegram_error(&self, error: &anyhow::Error) {
+        if error.downcast_ref::<teloxide::RequestError>().is_some() {
+            self.telegram_errors.inc();
+        }
+    }
+
+    pub fn history_error(&self, codename: &str) {
+        self.history_errors.with_label_values(&[codename]).inc();
+    }
+
+    pub fn render(&self, database_queue: usize) -> String {
+        self.database_queue.set(database_queue as i64);
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .inspect_err(|e| log::error!("Encode metrics error: {e:?}"))
+            .ok();
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+fn register<T: Collector + Clone + 'static>(
+    registry: &Registry,
+    metric: prometheus::Result<T>,
+) -> T {
+    let metric = metric.unwrap();
+    registry.register(Box::new(metric.clone())).unwrap();
+    metric
+}
+
+pub struct GaugeGuard(&'static IntGauge);
+
+impl GaugeGuard {
+    pub fn new(gauge: &'static IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
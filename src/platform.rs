@@ -1,4 +1,10 @@
-use std::sync::{Arc, LazyLock};
+use std::{
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
 
 use anyhow::anyhow;
 use log::warn;
@@ -16,7 +22,13 @@ use teloxide::{
     },
 };
 
-use crate::{config::Config, database::DatabaseHelper, types::AccessLevel};
+use crate::{
+    config::Config,
+    database::{DatabaseError, DatabaseHelper, DatabaseResult},
+    metrics::METRICS,
+    types::{AccessLevel, ClientRow, SubmitStatus},
+    web::session::{Session, SessionRegistry},
+};
 
 static PASSCODE_RE: LazyLock<regex::Regex> =
     LazyLock::new(|| regex::Regex::new(r"^[\w\d]{5,}$").unwrap());
@@ -24,14 +36,19 @@ static PASSCODE_RE: LazyLock<regex::Regex> =
 pub static TELEGRAM_ESCAPE_RE: LazyLock<regex::Regex> =
     LazyLock::new(|| regex::Regex::new(r"([_*\[\]\(\)~`>#\+-=|\{}\.!])").unwrap());
 
-static VALID_CODENAME: LazyLock<regex::Regex> =
+pub static VALID_CODENAME: LazyLock<regex::Regex> =
     LazyLock::new(|| regex::Regex::new(r"^(Agent_\d{5,}|[\w\d]{3,})$").unwrap());
 
+// Recording a posted code is retried in background with backoff up to this, it is safe to repeat
+const CONFIRM_BACKOFF: Duration = Duration::from_secs(60);
+
 #[derive(BotCommands, Clone)]
 #[command(rename_rule = "lowercase")]
 enum Command {
     Auth { code: String },
     Cookie { ops: String },
+    Credential { ops: String },
+    Clients,
     Log { id: String },
     Resent { code: String },
     Invite,
@@ -44,6 +61,8 @@ pub struct NecessaryArg {
     admin: Vec<ChatId>,
     totp: totp_rs::TOTP,
     target: i64,
+    dispatching: Arc<AtomicBool>,
+    sessions: SessionRegistry,
 }
 
 impl NecessaryArg {
@@ -58,13 +77,23 @@ impl NecessaryArg {
             admin,
             target,
             totp,
+            dispatching: Default::default(),
+            sessions: Default::default(),
         }
     }
 
+    pub fn dispatching(&self) -> bool {
+        self.dispatching.load(Ordering::Relaxed)
+    }
+
     pub fn database(&self) -> &DatabaseHelper {
         &self.database
     }
 
+    pub fn sessions(&self) -> &SessionRegistry {
+        &self.sessions
+    }
+
     pub fn admin(&self) -> &[ChatId] {
         &self.admin
     }
@@ -73,23 +102,24 @@ impl NecessaryArg {
         ChatId(self.target)
     }
 
-    pub async fn check_auth(&self, id: ChatId, level: AccessLevel) -> bool {
-        self.check_admin(id)
-            || level.required(
-                self.database()
-                    .user_query(id.0)
-                    .await
-                    .flatten()
-                    .map(|u| u.authorized())
-                    .unwrap_or(0),
-            )
+    pub async fn check_auth(&self, id: ChatId, level: AccessLevel) -> DatabaseResult<bool> {
+        if self.check_admin(id) {
+            return Ok(true);
+        }
+        Ok(level.required(
+            self.database()
+                .user_query(id.0)
+                .await?
+                .map(|u| u.authorized())
+                .unwrap_or(0),
+        ))
     }
-    pub async fn access_level(&self, id: ChatId) -> Option<AccessLevel> {
-        self.database
+    pub async fn access_level(&self, id: ChatId) -> DatabaseResult<Option<AccessLevel>> {
+        Ok(self
+            .database
             .user_query(id.0)
-            .await
-            .flatten()
-            .map(|u| AccessLevel::f_i32(u.authorized()))
+            .await?
+            .map(|u| AccessLevel::f_i32(u.authorized())))
     }
 
     pub fn check_admin(&self, id: ChatId) -> bool {
@@ -175,7 +205,7 @@ impl<'a> TryFrom<&'a str> for CookieOps<'a> {
         if !value.contains(' ') && !value.eq("query") {
             return Err(anyhow!("Missing space"));
         }
-        let group = value.trim().split_whitespace().collect::<Vec<_>>();
+        let group = value.split_whitespace().collect::<Vec<_>>();
         if !match group[0] {
             "enable" | "disable" => group.len() > 1,
             "modify" | "add" => group.len() > 3,
@@ -204,6 +234,27 @@ impl<'a> TryFrom<&'a str> for CookieOps<'a> {
     }
 }
 
+#[derive(Debug)]
+pub enum CredentialOps<'a> {
+    Issue(&'a str),
+    Revoke(&'a str),
+    List,
+}
+
+impl<'a> TryFrom<&'a str> for CredentialOps<'a> {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &'a str) -> Result<Self, Self::Error> {
+        let group = value.split_whitespace().collect::<Vec<_>>();
+        Ok(match (group.first().copied(), group.get(1).copied()) {
+            (Some("issue"), Some(codename)) => Self::Issue(codename),
+            (Some("revoke"), Some(codename)) => Self::Revoke(codename),
+            (Some("list") | None, _) => Self::List,
+            _ => return Err(anyhow!("Mismatch argument count / Unknown ops")),
+        })
+    }
+}
+
 pub fn bot(config: &Config) -> anyhow::Result<BotType> {
     let bot = Bot::new(config.platform().key());
     Ok(match config.platform().server() {
@@ -215,19 +266,23 @@ pub fn bot(config: &Config) -> anyhow::Result<BotType> {
 
 pub type BotType = DefaultParseMode<Bot>;
 
-pub async fn bot_run(
-    bot: BotType,
-    config: Config,
-    database: DatabaseHelper,
-    totp: totp_rs::TOTP,
-) -> anyhow::Result<()> {
-    let arg = Arc::new(NecessaryArg::new(
-        database,
-        config.admin().iter().map(|u| ChatId(*u)).collect(),
-        config.platform().target(),
-        totp,
-    ));
+// Tell the user why nothing happened instead of staying silent
+async fn report_database_error(bot: &BotType, chat: ChatId, result: &anyhow::Result<()>) {
+    let Some(e) = result
+        .as_ref()
+        .err()
+        .and_then(|e| e.downcast_ref::<DatabaseError>())
+    else {
+        return;
+    };
+    let text = format!("Database error: {e}");
+    bot.send_message(chat, TELEGRAM_ESCAPE_RE.replace_all(&text, "\\$1"))
+        .await
+        .inspect_err(|e| log::error!("Report database error: {e:?}"))
+        .ok();
+}
 
+pub async fn bot_run(bot: BotType, arg: Arc<NecessaryArg>) -> anyhow::Result<()> {
     let handle_message = Update::filter_message()
         .branch(
             dptree::entry()
@@ -235,19 +290,29 @@ pub async fn bot_run(
                 .filter_command::<Command>()
                 .endpoint(
                     |msg: Message, bot: BotType, arg: Arc<NecessaryArg>, cmd: Command| async move {
-                        match cmd {
+                        let (reply, chat) = (bot.clone(), msg.chat.id);
+                        let result = match cmd {
                             Command::Auth { code } => {
                                 handle_auth_command(bot, arg, msg, code).await
                             }
                             Command::Cookie { ops } => {
                                 handle_cookie_command(bot, arg, msg, ops).await
                             }
+                            Command::Credential { ops } => {
+                                handle_credential_command(bot, arg, msg, ops).await
+                            }
+                            Command::Clients => handle_clients_command(bot, arg, msg).await,
                             Command::Log { id } => handle_log_command(bot, msg, arg, id).await,
                             Command::Ping => handle_ping(bot, msg, arg).await,
                             Command::Resent { code } => handle_resent(bot, msg, arg, code).await,
                             Command::Invite => handle_get_invite(bot, msg, arg).await,
                         }
-                        .inspect_err(|e| log::error!("Handle command error: {e:?}"))
+                        .inspect_err(|e| {
+                            METRICS.telegram_error(e);
+                            log::error!("Handle command error: {e:?}")
+                        });
+                        report_database_error(&reply, chat, &result).await;
+                        result
                     },
                 ),
         )
@@ -258,7 +323,12 @@ pub async fn bot_run(
                 })
                 .endpoint(
                     |msg: Message, bot: BotType, arg: Arc<NecessaryArg>| async move {
-                        handle_message(bot, msg, arg).await
+                        let (reply, chat) = (bot.clone(), msg.chat.id);
+                        let result = handle_message(bot, msg, arg)
+                            .await
+                            .inspect_err(|e| METRICS.telegram_error(e));
+                        report_database_error(&reply, chat, &result).await;
+                        result
                     },
                 ),
         );
@@ -267,7 +337,9 @@ pub async fn bot_run(
         .filter(|q: CallbackQuery| q.data.is_some())
         .endpoint(
             |q: CallbackQuery, bot: BotType, arg: Arc<NecessaryArg>| async move {
-                handle_callback_query(bot, q, arg).await
+                handle_callback_query(bot, q, arg)
+                    .await
+                    .inspect_err(|e| METRICS.telegram_error(e))
             },
         );
     let dispatcher = Dispatcher::builder(
@@ -276,9 +348,11 @@ pub async fn bot_run(
             .branch(handle_message)
             .branch(handle_callback_query),
     )
-    .dependencies(dptree::deps![arg])
+    .dependencies(dptree::deps![arg.clone()])
     .default_handler(|_| async {});
 
+    arg.dispatching.store(true, Ordering::Relaxed);
+
     #[cfg(not(debug_assertions))]
     dispatcher.enable_ctrlc_handler().build().dispatch().await;
 
@@ -289,6 +363,8 @@ pub async fn bot_run(
         } => {}
         _ = tokio::signal::ctrl_c() => {}
     }
+
+    arg.dispatching.store(false, Ordering::Relaxed);
     Ok(())
 }
 
@@ -298,14 +374,7 @@ pub async fn handle_auth_command(
     msg: Message,
     code: String,
 ) -> anyhow::Result<()> {
-    if arg.check_admin(msg.chat.id)
-        || arg
-            .database()
-            .user_query(msg.chat.id.0)
-            .await
-            .flatten()
-            .is_some()
-    {
+    if arg.check_admin(msg.chat.id) || arg.database().user_query(msg.chat.id.0).await?.is_some() {
         return Ok(());
     }
 
@@ -331,7 +400,7 @@ pub async fn handle_auth_command(
         .reply_markup(mark_auth_keyboard(msg.chat.id.0))
         .await?;
     }
-    arg.database().user_add(msg.chat.id.0).await;
+    arg.database().user_add(msg.chat.id.0).await?;
     Ok(())
 }
 
@@ -341,7 +410,7 @@ pub async fn handle_cookie_command(
     msg: Message,
     ops: String,
 ) -> anyhow::Result<()> {
-    if !arg.check_auth(msg.chat.id, AccessLevel::Cookie).await {
+    if !arg.check_auth(msg.chat.id, AccessLevel::Cookie).await? {
         return Ok(());
     }
     let ops = match CookieOps::try_from(ops.as_str()) {
@@ -357,13 +426,14 @@ pub async fn handle_cookie_command(
                 || arg
                     .database()
                     .cookie_query_id(id.to_string())
-                    .await
-                    .flatten()
+                    .await?
                     .is_some_and(|c| c.belong_chat().eq(&msg.chat.id)))
             {
                 return Ok(());
             }
-            arg.database().cookie_toggle(id.to_string(), enabled).await;
+            arg.database()
+                .cookie_toggle(id.to_string(), enabled)
+                .await?;
 
             bot.send_message(msg.chat.id, format!("Toggle {id} to {enabled}"))
                 .await?;
@@ -379,8 +449,7 @@ pub async fn handle_cookie_command(
                 && !arg
                     .database()
                     .cookie_check_capacity(id.to_string(), msg.chat.id.0, 2)
-                    .await
-                    .unwrap_or(true)
+                    .await?
             {
                 bot.send_message(msg.chat.id, "Max cookie capacity exceed, if you want more capacity, please contact administrator").await?;
                 return Ok(());
@@ -393,21 +462,20 @@ pub async fn handle_cookie_command(
                     csrf.to_string(),
                     session.to_string(),
                 )
-                .await;
+                .await?;
 
             bot.send_message(msg.chat.id, format!("Updated {} cookie", id))
                 .await?;
         }
         CookieOps::Query(additional) => {
-            let cookies =
-                if additional.is_some_and(|s| s.eq("all")) && arg.check_admin(msg.chat.id) {
-                    arg.database().cookie_query_all(false).await
-                } else if arg.check_auth(msg.chat.id, AccessLevel::Cookie).await {
-                    arg.database().cookie_query(msg.chat.id.0).await
-                } else {
-                    return Ok(());
-                }
-                .unwrap();
+            let cookies = if additional.is_some_and(|s| s.eq("all")) && arg.check_admin(msg.chat.id)
+            {
+                arg.database().cookie_query_all(false).await
+            } else if arg.check_auth(msg.chat.id, AccessLevel::Cookie).await? {
+                arg.database().cookie_query(msg.chat.id.0).await
+            } else {
+                return Ok(());
+            }?;
 
             let cookies = cookies
                 .into_iter()
@@ -430,40 +498,150 @@ pub async fn handle_cookie_command(
     Ok(())
 }
 
-pub async fn handle_log_command(
+pub async fn handle_credential_command(
     bot: BotType,
-    msg: Message,
     arg: Arc<NecessaryArg>,
-    id: String,
+    msg: Message,
+    ops: String,
 ) -> anyhow::Result<()> {
     if !arg.check_admin(msg.chat.id) {
         return Ok(());
     }
-
-    match arg.database().log_query(id).await {
-        Some(v) => {
-            let text = v
-                .iter()
-                .map(|entry| entry.to_string())
-                .collect::<Vec<_>>()
-                .join("\n");
-            if text.is_empty() {
-                bot.send_message(msg.chat.id, "__Nothing to display__")
-                    .await?;
+    let ops = match CredentialOps::try_from(ops.as_str()) {
+        Ok(ops) => ops,
+        Err(e) => {
+            log::error!("Credential arg: {e:?}");
+            return Ok(());
+        }
+    };
+    match ops {
+        CredentialOps::Issue(codename) => {
+            if !VALID_CODENAME.is_match(codename) {
+                bot.send_message(msg.chat.id, "Invalid codename").await?;
                 return Ok(());
             }
-
-            bot.send_message(msg.chat.id, TELEGRAM_ESCAPE_RE.replace_all(&text, "\\$1"))
+            let (secret, token, credential) = ClientRow::issue()?;
+            let rotated = arg
+                .database()
+                .client_issue(codename.to_string(), credential)
                 .await?;
+            let text = if rotated {
+                // Sessions authenticated with the old secret must log in again
+                let kicked = arg.sessions().kick_codename(codename);
+                log::info!(
+                    "{} rotate credential for {codename}, kick {kicked} sessions",
+                    msg.chat.id.0
+                );
+                format!("Rotated credential for `{codename}`, kicked {kicked} sessions")
+            } else {
+                log::info!("{} issue credential for {codename}", msg.chat.id.0);
+                format!("Created credential for `{codename}`")
+            };
+            bot.send_message(
+                msg.chat.id,
+                format!("{text}: `{secret}`\nHTTP token: `{token}`"),
+            )
+            .await?;
         }
-        None => {
-            bot.send_message(msg.chat.id, "__Nothing to display__")
-                .await?;
+        CredentialOps::Revoke(codename) => {
+            if !VALID_CODENAME.is_match(codename) {
+                bot.send_message(msg.chat.id, "Invalid codename").await?;
+                return Ok(());
+            }
+            let revoked = arg.database().client_revoke(codename.to_string()).await?;
+            // Revoked credential must not keep an open stream
+            let kicked = arg.sessions().kick_codename(codename);
+            if kicked > 0 {
+                log::info!("Kick {kicked} sessions of revoked {codename}");
+            }
+            bot.send_message(
+                msg.chat.id,
+                if revoked {
+                    format!("Revoked `{codename}`, kicked {kicked} sessions")
+                } else {
+                    format!("`{codename}` not found")
+                },
+            )
+            .await?;
+        }
+        CredentialOps::List => {
+            let clients = arg
+                .database()
+                .client_query_all()
+                .await?
+                .into_iter()
+                .map(|client| client.to_string())
+                .collect::<Vec<_>>()
+                .join("\n");
+            bot.send_message(
+                msg.chat.id,
+                if clients.is_empty() {
+                    "Nothing to display".to_string()
+                } else {
+                    clients
+                },
+            )
+            .await?;
         }
     }
     Ok(())
 }
 
+pub async fn handle_clients_command(
+    bot: BotType,
+    arg: Arc<NecessaryArg>,
+    msg: Message,
+) -> anyhow::Result<()> {
+    if !arg.check_admin(msg.chat.id) {
+        return Ok(());
+    }
+    let sessions = arg.sessions().list();
+    if sessions.is_empty() {
+        bot.send_message(msg.chat.id, "No client online").await?;
+        return Ok(());
+    }
+    bot.send_message(
+        msg.chat.id,
+        sessions
+            .iter()
+            .map(|session| session.to_string())
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
+    .reply_markup(make_kick_keyboard(&sessions))
+    .await?;
+    Ok(())
+}
+
+pub async fn handle_log_command(
+    bot: BotType,
+    msg: Message,
+    arg: Arc<NecessaryArg>,
+    id: String,
+) -> anyhow::Result<()> {
+    if !arg.check_admin(msg.chat.id) {
+        return Ok(());
+    }
+
+    let text = arg
+        .database()
+        .log_query(id)
+        .await?
+        .iter()
+        .map(|entry| entry.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if text.is_empty() {
+        bot.send_message(msg.chat.id, "__Nothing to display__")
+            .await?;
+        return Ok(());
+    }
+
+    bot.send_message(msg.chat.id, TELEGRAM_ESCAPE_RE.replace_all(&text, "\\$1"))
+        .await?;
+    Ok(())
+}
+
 pub async fn handle_resent(
     bot: BotType,
     msg: Message,
@@ -473,9 +651,12 @@ pub async fn handle_resent(
     if !arg.check_admin(msg.chat.id) {
         return Ok(());
     }
-    arg.database().code_resent(code.clone()).await;
-    bot.send_message(msg.chat.id, format!("`{code}` resent",))
-        .await?;
+    let text = if arg.database().code_resent(code.clone()).await? {
+        format!("`{code}` resent")
+    } else {
+        format!("`{code}` not found")
+    };
+    bot.send_message(msg.chat.id, text).await?;
     Ok(())
 }
 
@@ -487,7 +668,7 @@ pub async fn handle_ping(bot: BotType, msg: Message, arg: Arc<NecessaryArg>) ->
             id = msg.chat.id.0,
             is_authorized = arg
                 .access_level(msg.chat.id)
-                .await
+                .await?
                 .map(|l| l.into())
                 .unwrap_or("Not found"),
             is_admin = arg.check_admin(msg.chat.id),
@@ -519,44 +700,145 @@ pub async fn handle_get_invite(
     Ok(())
 }
 
+pub async fn submit_code(
+    bot: &BotType,
+    arg: &NecessaryArg,
+    code: &str,
+) -> anyhow::Result<SubmitStatus> {
+    let status = forward_code(bot, arg, code).await?;
+    METRICS.submit(status);
+    Ok(status)
+}
+
+async fn forward_code(
+    bot: &BotType,
+    arg: &NecessaryArg,
+    code: &str,
+) -> anyhow::Result<SubmitStatus> {
+    if !PASSCODE_RE.is_match(code) {
+        return Ok(SubmitStatus::Rejected);
+    }
+    // Reserve first so concurrent submissions of the same code post only once
+    match arg.database().code_reserve(code.to_string()).await {
+        Ok(Some(true)) => return Ok(SubmitStatus::AlreadyFR),
+        Ok(Some(false)) => return Ok(SubmitStatus::Duplicate),
+        Ok(None) => {}
+        // Not released even on timeout, that could drop the reservation of another submitter.
+        // A reservation applied after the timeout expires on its own
+        Err(e) => return Err(e.into()),
+    }
+    let msg = match bot.send_message(arg.target(), format!("`{}`", code)).await {
+        Ok(msg) => msg,
+        Err(e) => {
+            release_code(arg, code).await;
+            return Err(e.into());
+        }
+    };
+    // Message is already in the channel, keep trying without holding up the submitter
+    if let Err(e) = arg
+        .database()
+        .code_confirm(code.to_string(), msg.id.0)
+        .await
+    {
+        warn!(
+            "Record {code} posted as message {} error: {e}, retry in background",
+            msg.id.0
+        );
+        tokio::spawn(confirm_later(
+            arg.database().clone(),
+            code.to_string(),
+            msg.id.0,
+        ));
+    }
+    Ok(SubmitStatus::Accepted)
+}
+
+// Posted code must end up in database, otherwise clients never receive it
+async fn confirm_later(database: DatabaseHelper, code: String, message_id: i32) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(CONFIRM_BACKOFF);
+        // Reservation must outlive the retries, or the code could be posted again meanwhile
+        if let Err(e) = database.code_hold(code.clone()).await {
+            warn!("Hold {code} reservation error: {e}");
+        }
+        match database.code_confirm(code.clone(), message_id).await {
+            Ok(()) => {
+                log::info!("Recorded {code} posted as message {message_id}");
+                break;
+            }
+            Err(DatabaseError::Closed) => {
+                log::error!(
+                    "Database closed, {code} posted as message {message_id} is not recorded"
+                );
+                break;
+            }
+            // Retrying cannot succeed, the message has to be recorded by hand
+            Err(e @ DatabaseError::Constraint(_)) => {
+                log::error!(
+                    "Record {code} posted as message {message_id} error: {e}, needs manual reconciliation"
+                );
+                break;
+            }
+            Err(e) => warn!("Record {code} error: {e}, retry"),
+        }
+    }
+}
+
+async fn release_code(arg: &NecessaryArg, code: &str) {
+    if let Err(e) = arg.database().code_release(code.to_string()).await {
+        log::error!("Release {code} reservation error: {e}");
+    }
+}
+
 pub async fn handle_message(
     bot: BotType,
     msg: Message,
     arg: Arc<NecessaryArg>,
 ) -> anyhow::Result<()> {
-    if !arg.check_auth(msg.chat.id, AccessLevel::Send).await {
+    if !arg.check_auth(msg.chat.id, AccessLevel::Send).await? {
         return Ok(());
     }
     for code in msg.text().unwrap().lines() {
-        if !PASSCODE_RE.is_match(code) {
-            warn!(
-                "Ignore wrong format passcode {} sent by {}({})",
-                code,
-                msg.chat.first_name().unwrap_or("<NO NAME>"),
-                msg.chat.id.0
-            );
-            continue;
-        }
-        if let Some(Some(c)) = arg.database().code_query(code.to_string()).await {
-            if c.is_fr() {
-                bot.send_message(msg.chat.id, format!("`{}` already FR", code))
-                    .await?;
-            } else {
+        match submit_code(&bot, &arg, code).await? {
+            SubmitStatus::Accepted | SubmitStatus::Failed => {}
+            SubmitStatus::Duplicate => {
                 bot.send_message(msg.chat.id, format!("`{}` has been sent", code))
                     .reply_markup(make_fr_keyboard(code))
                     .await?;
             }
-        } else {
-            let msg = bot
-                .send_message(arg.target(), format!("`{}`", code))
-                .await?;
-            arg.database.code_add(code.to_string(), msg.id.0).await;
+            SubmitStatus::AlreadyFR => {
+                bot.send_message(msg.chat.id, format!("`{}` already FR", code))
+                    .await?;
+            }
+            SubmitStatus::Rejected => {
+                warn!(
+                    "Ignore wrong format passcode {} sent by {}({})",
+                    code,
+                    msg.chat.first_name().unwrap_or("<NO NAME>"),
+                    msg.chat.id.0
+                );
+            }
         }
     }
 
     Ok(())
 }
 
+pub async fn mark_code_fr(bot: &BotType, arg: &NecessaryArg, code: &str) -> anyhow::Result<()> {
+    if let Some(code) = arg.database().code_fr(code.to_string()).await? {
+        bot.edit_message_text(
+            arg.target(),
+            MessageId(code.message_id()),
+            format!("<del>{}</del>", code.code()),
+        )
+        .parse_mode(ParseMode::Html)
+        .await?;
+    }
+    Ok(())
+}
+
 pub async fn handle_callback_query(
     bot: BotType,
     msg: CallbackQuery,
@@ -586,29 +868,36 @@ pub async fn handle_callback_query(
                                     }
                                 },
                             )
-                            .await;
+                            .await?;
                         bot.send_message(ChatId(id), "Talk power granted").await?;
                         log::info!("{} grant {} power", msg.from.id.0, id);
                     }
                 }
                 "reject" => {
                     if let Some(id) = cq.target_i64() {
-                        arg.database().user_revoke(id).await;
+                        arg.database().user_revoke(id).await?;
                     }
                 }
                 _ => {}
             },
             "code" => {
                 if cq.action.eq("fr") {
-                    if let Some(Some(code)) = arg.database().code_fr(cq.target.to_string()).await {
-                        bot.edit_message_text(
-                            arg.target(),
-                            MessageId(code.message_id()),
-                            format!("<del>{}</del>", code.code()),
-                        )
-                        .parse_mode(ParseMode::Html)
-                        .await?;
-                    }
+                    mark_code_fr(&bot, &arg, cq.target).await?;
+                }
+            }
+            "session" => {
+                if cq.action.eq("kick")
+                    && arg.check_admin(ChatId(msg.from.id.0 as i64))
+                    && let Some(id) = cq.target_i64()
+                {
+                    let text = match arg.sessions().kick(id as u64) {
+                        Some(session) => {
+                            log::info!("{} kick session {session}", msg.from.id.0);
+                            format!("Kicked {session}")
+                        }
+                        None => format!("Session \\#{id} already gone"),
+                    };
+                    bot.send_message(msg.from.id, text).await?;
                 }
             }
             _ => {
@@ -625,6 +914,15 @@ pub async fn handle_callback_query(
     Ok(())
 }
 
+pub fn make_kick_keyboard(sessions: &[Session]) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(sessions.iter().map(|session| {
+        [InlineKeyboardButton::callback(
+            format!("Kick #{} {}", session.id(), session.codename()),
+            format!("session kick {}", session.id()),
+        )]
+    }))
+}
+
 pub fn make_fr_keyboard(code: &str) -> InlineKeyboardMarkup {
     InlineKeyboardMarkup::new([[InlineKeyboardButton::callback(
         "Mark as FR",
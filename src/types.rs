@@ -1,13 +1,21 @@
-use argon2::{Argon2, PasswordVerifier};
+use argon2::{
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, Version,
+    password_hash::{ParamsString, SaltString},
+};
 use chrono::DateTime;
+use hmac::{
+    Hmac, Mac,
+    digest::{CtOutput, Output},
+};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::prelude::FromRow;
 use teloxide::types::ChatId;
 
 use crate::platform::TELEGRAM_ESCAPE_RE;
 
 #[allow(dead_code)]
-#[derive(Clone, Copy, Debug, FromRow)]
+#[derive(Clone, Copy, Debug, FromRow, Serialize)]
 pub struct User {
     id: i64,
     authorized: i64,
@@ -21,12 +29,18 @@ impl User {
 
 #[derive(Clone, Debug, FromRow)]
 pub struct CodeRow {
+    seq: i64,
     code: String,
     fr: i64,
     message_id: i32,
+    timestamp: i64,
 }
 
 impl CodeRow {
+    pub fn seq(&self) -> i64 {
+        self.seq
+    }
+
     pub fn is_fr(&self) -> bool {
         self.fr == 1
     }
@@ -40,35 +54,49 @@ impl CodeRow {
     }
 }
 
-#[derive(Clone, Debug, FromRow)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CodeEvent {
+    code: String,
+    message_id: i32,
+    timestamp: i64,
+    seq: i64,
+}
+
+impl CodeEvent {
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    pub fn seq(&self) -> i64 {
+        self.seq
+    }
+}
+
+impl From<CodeRow> for CodeEvent {
+    fn from(row: CodeRow) -> Self {
+        Self {
+            code: row.code,
+            message_id: row.message_id,
+            timestamp: row.timestamp,
+            seq: row.seq,
+        }
+    }
+}
+
+// Cookie secrets are never loaded, so it is safe to serialize
+#[derive(Clone, Debug, FromRow, Serialize)]
 pub struct Cookie {
     id: String,
-    csrf_token: String,
-    session_id: String,
     last_login: i64,
     belong: i64,
     enabled: bool,
 }
 
 impl Cookie {
-    pub const RECENTLY: i64 = 7200;
-
-    pub fn csrf_token(&self) -> &str {
-        &self.csrf_token
-    }
-
-    pub fn session_id(&self) -> &str {
-        &self.session_id
-    }
-
     pub fn id(&self) -> &str {
         &self.id
     }
 
-    pub fn login_recently(&self, limit: i64) -> bool {
-        kstool::time::get_current_second() as i64 - self.last_login < limit
-    }
-
     pub fn belong(&self) -> i64 {
         self.belong
     }
@@ -105,8 +133,13 @@ impl std::fmt::Display for Cookie {
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Auth {
-    hash: String,
     codename: String,
+    nonce: String,
+    response: String,
+    #[serde(default)]
+    last_seq: Option<i64>,
+    #[serde(default)]
+    last_timestamp: Option<i64>,
 }
 
 impl Auth {
@@ -114,34 +147,254 @@ impl Auth {
         &self.codename
     }
 
-    pub fn check(&self, origin: &str) -> bool {
-        let origin_hash = match argon2::PasswordHash::new(origin) {
-            Ok(hash) => hash,
-            Err(e) => {
-                log::error!("Original password parse error: {e:?}");
-                return false;
-            }
-        };
-        Argon2::default()
-            .verify_password(self.hash.as_bytes(), &origin_hash)
-            .is_ok()
+    pub fn nonce(&self) -> &str {
+        &self.nonce
+    }
+
+    pub fn resume(&self) -> Option<(Option<i64>, Option<i64>)> {
+        (self.last_seq.is_some() || self.last_timestamp.is_some())
+            .then_some((self.last_seq, self.last_timestamp))
+    }
+
+    pub fn check(&self, client: &ClientRow) -> bool {
+        hex::decode(&self.response)
+            .is_ok_and(|proof| client.verify_proof(self.nonce.as_bytes(), &proof))
     }
 }
 
-impl TryFrom<&str> for Auth {
-    type Error = serde_json::Error;
+const CLIENT_KEY_LABEL: &[u8] = b"Client Key";
+const HTTP_TOKEN_LABEL: &[u8] = b"http";
+
+// What server keeps of a credential, SCRAM style: argon2 setting, SHA256(client key) and
+// SHA256(HTTP token). None of them answers a challenge or is accepted as a token
+#[derive(Clone, Debug)]
+pub struct ClientCredential {
+    setting: String,
+    stored_key: String,
+    token_hash: String,
+}
+
+impl ClientCredential {
+    // From argon2 PHC string of the secret, returns HTTP token alongside
+    pub fn from_hash(hash: &str) -> Option<(Self, String)> {
+        let mut hash = PasswordHash::new(hash)
+            .inspect_err(|e| log::error!("Password hash parse error: {e:?}"))
+            .ok()?;
+        let salted = hash.hash.take()?;
+        let client_key = Self::derive(salted.as_bytes(), CLIENT_KEY_LABEL)?;
+        let token = Self::derive(salted.as_bytes(), HTTP_TOKEN_LABEL)?;
+        Some((
+            Self {
+                setting: hash.to_string(),
+                stored_key: hex::encode(Sha256::digest(client_key)),
+                token_hash: hex::encode(Sha256::digest(&token)),
+            },
+            hex::encode(token),
+        ))
+    }
+
+    fn derive(salted: &[u8], label: &[u8]) -> Option<Vec<u8>> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(salted).ok()?;
+        mac.update(label);
+        Some(mac.finalize().into_bytes().to_vec())
+    }
+
+    pub fn setting(&self) -> &str {
+        &self.setting
+    }
+
+    pub fn stored_key(&self) -> &str {
+        &self.stored_key
+    }
 
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        serde_json::from_str(value)
+    pub fn token_hash(&self) -> &str {
+        &self.token_hash
     }
 }
 
 #[derive(Clone, Debug, FromRow)]
+pub struct ClientRow {
+    codename: String,
+    setting: String,
+    stored_key: String,
+    token_hash: String,
+    created: i64,
+    last_seen: Option<i64>,
+}
+
+impl ClientRow {
+    // Returns secret, HTTP token and what server stores
+    pub fn issue() -> anyhow::Result<(String, String, ClientCredential)> {
+        let secret = rand::Rng::sample_iter(rand::rng(), rand::distr::Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect::<String>();
+        let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+            .map_err(|e| anyhow::anyhow!("Salt encode error: {e:?}"))?;
+        let (credential, token) = ClientCredential::from_hash(&Self::hash_secret(&secret, &salt)?)
+            .ok_or_else(|| anyhow::anyhow!("Derive client keys"))?;
+        Ok((secret, token, credential))
+    }
+
+    fn hash_secret(secret: &str, salt: &SaltString) -> anyhow::Result<String> {
+        Ok(Argon2::default()
+            .hash_password(secret.as_bytes(), salt)
+            .map_err(|e| anyhow::anyhow!("Hash password error: {e:?}"))?
+            .to_string())
+    }
+
+    // HTTP basic auth password, derived from the salted secret so the secret never travels in a header
+    // and a leaked token cannot answer stream challenges. Only its digest is stored
+    pub fn verify_token(&self, token: &str) -> bool {
+        hex::decode(token).is_ok_and(|token| digest_eq(&token, &self.token_hash))
+    }
+
+    // Proof is client key XOR HMAC(stored key, nonce), recovered client key must hash to stored key
+    fn verify_proof(&self, nonce: &[u8], proof: &[u8]) -> bool {
+        let Ok(stored_key) = hex::decode(&self.stored_key) else {
+            return false;
+        };
+        let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(&stored_key) else {
+            return false;
+        };
+        mac.update(nonce);
+        let signature = mac.finalize().into_bytes();
+        if proof.len() != signature.len() {
+            return false;
+        }
+        let client_key = proof
+            .iter()
+            .zip(signature)
+            .map(|(proof, signature)| proof ^ signature)
+            .collect::<Vec<_>>();
+        digest_eq(&client_key, &self.stored_key)
+    }
+
+    // PHC string without the hash part, client derive its keys from it
+    pub fn setting(&self) -> &str {
+        &self.setting
+    }
+
+    // Stable but meaningless setting for unknown codename, avoid leaking which codename exists.
+    // Assembled from default params without hashing, so unknown codenames cost nothing
+    pub fn fake_setting(codename: &str) -> Option<String> {
+        let salt = SaltString::encode_b64(&Sha256::digest(codename.as_bytes())[..16]).ok()?;
+        let params = Params::default();
+        Some(
+            PasswordHash {
+                algorithm: Algorithm::default().ident(),
+                version: Some(Version::default().into()),
+                params: ParamsString::try_from(&params).ok()?,
+                salt: Some(salt.as_salt()),
+                hash: None,
+            }
+            .to_string(),
+        )
+    }
+}
+
+// Constant time comparison of SHA256(data) with a stored hex digest
+fn digest_eq(data: &[u8], expected: &str) -> bool {
+    let Ok(expected) = hex::decode(expected) else {
+        return false;
+    };
+    expected.len() == Sha256::output_size()
+        && CtOutput::<Sha256>::new(Sha256::digest(data))
+            == CtOutput::new(Output::<Sha256>::clone_from_slice(&expected))
+}
+
+impl std::fmt::Display for ClientRow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{}` issued at {}",
+            self.codename,
+            TELEGRAM_ESCAPE_RE.replace_all(
+                HistoryRow::timestamp_to_string(self.created).as_str(),
+                "\\$1"
+            )
+        )?;
+        if let Some(last_seen) = self.last_seen {
+            write!(
+                f,
+                ", last seen {}",
+                TELEGRAM_ESCAPE_RE
+                    .replace_all(HistoryRow::timestamp_to_string(last_seen).as_str(), "\\$1")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubmitStatus {
+    Accepted,
+    Duplicate,
+    #[serde(rename = "already_fr")]
+    AlreadyFR,
+    Rejected,
+    // Forwarding failed, submit again later
+    Failed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, strum::IntoStaticStr)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum RedeemStatus {
+    Success,
+    AlreadyRedeemed,
+    Invalid,
+    FullyRedeemed,
+    CookieExpired,
+}
+
+impl RedeemStatus {
+    pub fn error(&self) -> Option<String> {
+        match self {
+            Self::Success => None,
+            _ => Some(<&str>::from(self).to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RedeemReport {
+    code: String,
+    codename: String,
+    status: RedeemStatus,
+    #[serde(default)]
+    reward: Option<serde_json::Value>,
+}
+
+impl RedeemReport {
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    pub fn codename(&self) -> &str {
+        &self.codename
+    }
+
+    pub fn status(&self) -> RedeemStatus {
+        self.status
+    }
+
+    pub fn reward(&self) -> Option<String> {
+        self.reward.as_ref().map(|reward| match reward {
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        })
+    }
+}
+
+#[derive(Clone, Debug, FromRow, Serialize)]
 pub struct HistoryRow {
     timestamp: i64,
     id: String,
     code: String,
     error: Option<String>,
+    reward: Option<String>,
 }
 
 impl HistoryRow {
@@ -171,6 +424,10 @@ impl HistoryRow {
     pub fn error(&self) -> Option<&str> {
         self.error.as_deref()
     }
+
+    pub fn reward(&self) -> Option<&str> {
+        self.reward.as_deref()
+    }
 }
 
 impl std::fmt::Display for HistoryRow {
@@ -182,7 +439,11 @@ impl std::fmt::Display for HistoryRow {
             self.id(),
             self.code(),
             self.error().unwrap_or("N/A")
-        )
+        )?;
+        if let Some(reward) = self.reward() {
+            write!(f, " {reward}")?;
+        }
+        Ok(())
     }
 }
 
@@ -227,20 +488,17 @@ impl VStats {
 mod access_level {
 
     use enum_primitive_derive::Primitive;
+    use serde::Deserialize;
 
-    #[derive(Copy, Clone, Debug, strum::IntoStaticStr, Primitive)]
+    #[derive(Copy, Clone, Debug, Default, Deserialize, strum::IntoStaticStr, Primitive)]
+    #[serde(rename_all = "snake_case")]
     pub enum AccessLevel {
+        #[default]
         NoAccess = 0,
         Cookie = 1,
         Send = 2,
         All = 31,
     }
-    impl Default for AccessLevel {
-        fn default() -> Self {
-            Self::NoAccess
-        }
-    }
-
     impl AccessLevel {
         pub fn required(&self, input: i32) -> bool {
             *self as i32 & input > 0
@@ -301,3 +559,83 @@ mod cookie_querier {
 
 pub use access_level::AccessLevel;
 //pub use cookie_querier::CookieQuerier;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_challenge_response() {
+        let (secret, token, credential) = ClientRow::issue().unwrap();
+        let client = ClientRow {
+            codename: "redeemer".to_string(),
+            setting: credential.setting().to_string(),
+            stored_key: credential.stored_key().to_string(),
+            token_hash: credential.token_hash().to_string(),
+            created: 0,
+            last_seen: None,
+        };
+        let hmac = |key: &[u8], data: &[u8]| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+            mac.update(data);
+            mac.finalize().into_bytes().to_vec()
+        };
+        // Client side: derive keys from setting and secret, then blind client key with the nonce
+        let setting = PasswordHash::new(client.setting()).unwrap();
+        let salted = Argon2::default()
+            .hash_password(secret.as_bytes(), setting.salt.unwrap())
+            .unwrap()
+            .hash
+            .unwrap();
+        let client_key = hmac(salted.as_bytes(), b"Client Key");
+        let stored_key = Sha256::digest(&client_key).to_vec();
+        assert_eq!(hex::encode(&stored_key), client.stored_key);
+        let proof = |key: &[u8], nonce: &str| {
+            hex::encode(
+                key.iter()
+                    .zip(hmac(&stored_key, nonce.as_bytes()))
+                    .map(|(key, signature)| key ^ signature)
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        let auth = |nonce: &str, response: String| -> Auth {
+            serde_json::from_value(serde_json::json!({
+                "codename": "redeemer", "nonce": nonce, "response": response
+            }))
+            .unwrap()
+        };
+        assert!(auth("nonce", proof(&client_key, "nonce")).check(&client));
+        assert!(!auth("other", proof(&client_key, "nonce")).check(&client));
+        // What database holds is not enough to answer
+        assert!(!auth("nonce", proof(&stored_key, "nonce")).check(&client));
+
+        // HTTP token is derived from the same salted secret, the secret itself is refused
+        assert_eq!(token, hex::encode(hmac(salted.as_bytes(), b"http")));
+        assert!(client.verify_token(&token));
+        assert!(!client.verify_token(&secret));
+        assert!(!client.verify_token(&client.token_hash));
+
+        // Fake setting looks exactly like a real one with the same salt
+        let salt = SaltString::encode_b64(&Sha256::digest(b"ghost")[..16]).unwrap();
+        let hash = ClientRow::hash_secret("", &salt).unwrap();
+        let mut real = PasswordHash::new(&hash).unwrap();
+        real.hash = None;
+        assert_eq!(ClientRow::fake_setting("ghost"), Some(real.to_string()));
+    }
+
+    #[test]
+    fn test_cookie_json() {
+        let cookie = Cookie {
+            id: "agent".to_string(),
+            last_login: 0,
+            belong: 1,
+            enabled: true,
+        };
+        let json = serde_json::to_value(&cookie).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"id": "agent", "last_login": 0, "belong": 1, "enabled": true})
+        );
+    }
+}
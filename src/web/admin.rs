@@ -0,0 +1,139 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, Query},
+    http::StatusCode,
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{Authorization, authorization::Basic},
+};
+use log::info;
+
+use crate::{
+    database::{DatabaseError, DatabaseResult},
+    platform::VALID_CODENAME,
+    types::{Cookie, HistoryRow, User},
+};
+
+use super::{
+    route::{WebContext, check_admin},
+    types::{ApproveRequest, ClientIp, CookieFilter, CookieRequest, HistoryQuery, ToggleRequest},
+};
+
+// Database gone or too slow is unavailable, a broken constraint is a conflict
+fn database_result<T>(result: DatabaseResult<T>) -> Result<T, StatusCode> {
+    result.map_err(|e| match e {
+        DatabaseError::Closed | DatabaseError::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+        DatabaseError::Constraint(_) => StatusCode::CONFLICT,
+        DatabaseError::Sqlx(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    })
+}
+
+pub async fn handle_user_approve(
+    ClientIp(ip): ClientIp,
+    TypedHeader(Authorization(authorization)): TypedHeader<Authorization<Basic>>,
+    Extension(ctx): Extension<WebContext>,
+    Path(user): Path<i64>,
+    Json(request): Json<ApproveRequest>,
+) -> Result<Json<User>, StatusCode> {
+    check_admin(&ctx, ip, &authorization).await?;
+    database_result(ctx.database().user_approve(user, request.level()).await)?;
+    info!(
+        "{} grant {user} {:?} power",
+        authorization.username(),
+        request.level()
+    );
+    query_user(&ctx, user).await
+}
+
+pub async fn handle_user_revoke(
+    ClientIp(ip): ClientIp,
+    TypedHeader(Authorization(authorization)): TypedHeader<Authorization<Basic>>,
+    Extension(ctx): Extension<WebContext>,
+    Path(user): Path<i64>,
+) -> Result<Json<User>, StatusCode> {
+    check_admin(&ctx, ip, &authorization).await?;
+    database_result(ctx.database().user_revoke(user).await)?;
+    info!("{} revoke {user} power", authorization.username());
+    query_user(&ctx, user).await
+}
+
+// User row as it stands after a change
+async fn query_user(ctx: &WebContext, user: i64) -> Result<Json<User>, StatusCode> {
+    database_result(ctx.database().user_query(user).await)?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+pub async fn handle_cookie_list(
+    ClientIp(ip): ClientIp,
+    TypedHeader(Authorization(authorization)): TypedHeader<Authorization<Basic>>,
+    Extension(ctx): Extension<WebContext>,
+    Query(filter): Query<CookieFilter>,
+) -> Result<Json<Vec<Cookie>>, StatusCode> {
+    check_admin(&ctx, ip, &authorization).await?;
+    database_result(ctx.database().cookie_query_all(filter.enabled_only()).await).map(Json)
+}
+
+pub async fn handle_cookie_set(
+    ClientIp(ip): ClientIp,
+    TypedHeader(Authorization(authorization)): TypedHeader<Authorization<Basic>>,
+    Extension(ctx): Extension<WebContext>,
+    Path(id): Path<String>,
+    Json(request): Json<CookieRequest>,
+) -> Result<StatusCode, StatusCode> {
+    check_admin(&ctx, ip, &authorization).await?;
+    if !VALID_CODENAME.is_match(&id) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    // Cookie owned by another user is left untouched, same as the bot command
+    if !database_result(
+        ctx.database()
+            .cookie_set(
+                request.user(),
+                id.to_lowercase(),
+                request.csrf_token().to_string(),
+                request.session_id().to_string(),
+            )
+            .await,
+    )? {
+        return Err(StatusCode::CONFLICT);
+    }
+    info!(
+        "{} update {id} cookie for {}",
+        authorization.username(),
+        request.user()
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn handle_cookie_toggle(
+    ClientIp(ip): ClientIp,
+    TypedHeader(Authorization(authorization)): TypedHeader<Authorization<Basic>>,
+    Extension(ctx): Extension<WebContext>,
+    Path(id): Path<String>,
+    Json(request): Json<ToggleRequest>,
+) -> Result<StatusCode, StatusCode> {
+    check_admin(&ctx, ip, &authorization).await?;
+    let database = ctx.database();
+    if database_result(database.cookie_query_id(id.clone()).await)?.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    database_result(database.cookie_toggle(id.clone(), request.enabled()).await)?;
+    info!(
+        "{} toggle {id} to {}",
+        authorization.username(),
+        request.enabled()
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn handle_history(
+    ClientIp(ip): ClientIp,
+    TypedHeader(Authorization(authorization)): TypedHeader<Authorization<Basic>>,
+    Extension(ctx): Extension<WebContext>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<HistoryRow>>, StatusCode> {
+    check_admin(&ctx, ip, &authorization).await?;
+    database_result(ctx.database().log_query(query.id().to_string()).await).map(Json)
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::config::Lockout;
+
+#[derive(Clone, Debug, Default)]
+pub struct NonceStore {
+    inner: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl NonceStore {
+    const LIFETIME: Duration = Duration::from_secs(60);
+
+    pub fn issue(&self) -> String {
+        let nonce = hex::encode(rand::random::<[u8; 32]>());
+        let mut inner = self.inner.lock().unwrap();
+        inner.retain(|_, issued| issued.elapsed() < Self::LIFETIME);
+        inner.insert(nonce.clone(), Instant::now());
+        nonce
+    }
+
+    pub fn consume(&self, nonce: &str) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .remove(nonce)
+            .is_some_and(|issued| issued.elapsed() < Self::LIFETIME)
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+enum FailureKey {
+    Ip(IpAddr),
+    Codename(String),
+}
+
+impl FailureKey {
+    fn pair(ip: IpAddr, codename: &str) -> [Self; 2] {
+        [Self::Ip(ip), Self::Codename(codename.to_string())]
+    }
+}
+
+impl std::fmt::Display for FailureKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "IP {ip}"),
+            Self::Codename(codename) => write!(f, "codename {codename}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Failure {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+// Failure counters keyed by both IP and codename, so neither rotating IPs nor codenames helps
+#[derive(Clone, Debug)]
+pub struct AuthGuard {
+    lockout: Lockout,
+    inner: Arc<Mutex<HashMap<FailureKey, Failure>>>,
+}
+
+impl AuthGuard {
+    pub fn new(lockout: Lockout) -> Self {
+        Self {
+            lockout,
+            inner: Default::default(),
+        }
+    }
+
+    pub fn locked(&self, ip: IpAddr, codename: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let inner = self.inner.lock().unwrap();
+        FailureKey::pair(ip, codename)
+            .iter()
+            .filter_map(|key| inner.get(key)?.locked_until)
+            .filter_map(|until| until.checked_duration_since(now))
+            .max()
+    }
+
+    // Returns keys just reached alert threshold
+    pub fn failure(&self, ip: IpAddr, codename: &str) -> Vec<String> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        inner.retain(|_, failure| now.duration_since(failure.last) < self.lockout.max());
+
+        let mut alerts = Vec::new();
+        for key in FailureKey::pair(ip, codename) {
+            let failure = inner.entry(key.clone()).or_insert(Failure {
+                count: 0,
+                last: now,
+                locked_until: None,
+            });
+            failure.count += 1;
+            failure.last = now;
+
+            let threshold = self.lockout.threshold();
+            if threshold > 0 && failure.count >= threshold {
+                let duration = self
+                    .lockout
+                    .base()
+                    .saturating_mul(2u32.saturating_pow(failure.count - threshold))
+                    .min(self.lockout.max());
+                failure.locked_until = Some(now + duration);
+            }
+            if self.lockout.alert() == Some(failure.count) {
+                alerts.push(key.to_string());
+            }
+        }
+        alerts
+    }
+
+    pub fn success(&self, ip: IpAddr, codename: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        for key in FailureKey::pair(ip, codename) {
+            inner.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, time::Duration};
+
+    use super::AuthGuard;
+
+    #[test]
+    fn test_lockout() {
+        let guard = AuthGuard::new(
+            toml::from_str("threshold = 2\nbase = 10\nmax = 25\nalert = 3").unwrap(),
+        );
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let within = |remaining: Option<Duration>, secs: u64| {
+            remaining.is_some_and(|d| d <= Duration::from_secs(secs) && d.as_secs() + 1 >= secs)
+        };
+
+        assert!(guard.failure(ip, "alice").is_empty());
+        assert_eq!(guard.locked(ip, "alice"), None);
+
+        guard.failure(ip, "alice");
+        assert!(within(guard.locked(ip, "alice"), 10));
+        // Same IP with another codename, or same codename from another IP
+        assert!(within(guard.locked(ip, "bob"), 10));
+        assert!(within(guard.locked(other, "alice"), 10));
+        assert_eq!(guard.locked(other, "bob"), None);
+
+        assert_eq!(
+            guard.failure(ip, "alice"),
+            vec!["IP 192.0.2.1".to_string(), "codename alice".to_string()]
+        );
+        assert!(within(guard.locked(ip, "alice"), 20));
+        assert!(guard.failure(ip, "alice").is_empty());
+        assert!(within(guard.locked(ip, "alice"), 25));
+
+        guard.success(ip, "alice");
+        assert_eq!(guard.locked(ip, "alice"), None);
+    }
+}
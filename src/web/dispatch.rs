@@ -0,0 +1,320 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use log::{error, info, warn};
+use tokio::sync::{Notify, broadcast};
+
+use crate::{
+    config::{Dispatch, DispatchMode},
+    database::{BroadcastEvent, DatabaseHelper},
+    types::CodeEvent,
+};
+
+use super::session::SessionRegistry;
+
+// Unfinished jobs of a cookie, code to assigned time
+type Jobs = HashMap<String, Instant>;
+
+// Codes short of `per_code` cookies kept for sessions coming online, oldest are dropped beyond this
+const BACKLOG_LIMIT: usize = 256;
+
+// Code which has not reached enough cookies yet
+#[derive(Debug)]
+struct Unassigned {
+    code: CodeEvent,
+    cookies: HashSet<String>,
+}
+
+// Assign each code to a few enabled cookies, instead of letting every client redeem with every cookie
+#[derive(Clone, Debug)]
+pub struct Dispatcher {
+    config: Dispatch,
+    pending: Arc<Mutex<HashMap<String, Jobs>>>,
+    online: Arc<Notify>,
+}
+
+impl Dispatcher {
+    pub fn new(config: Dispatch) -> Self {
+        Self {
+            config,
+            pending: Default::default(),
+            online: Default::default(),
+        }
+    }
+
+    pub fn targeted(&self) -> bool {
+        self.config.mode() == DispatchMode::Targeted
+    }
+
+    // Called when a session authenticates, so codes waiting for a cookie are assigned again
+    pub fn online(&self) {
+        self.online.notify_one();
+    }
+
+    pub async fn run(
+        self,
+        database: DatabaseHelper,
+        sessions: SessionRegistry,
+        mut broadcast: broadcast::Receiver<BroadcastEvent>,
+    ) {
+        let mut backlog = Vec::new();
+        loop {
+            tokio::select! {
+                event = broadcast.recv() => match event {
+                    Ok(BroadcastEvent::NewCode(code) | BroadcastEvent::Resend(code)) => {
+                        backlog.retain(|job: &Unassigned| job.code.code() != code.code());
+                        let job = Unassigned {
+                            code,
+                            cookies: HashSet::new(),
+                        };
+                        if let Some(job) = self.assign(&database, &sessions, job).await {
+                            if backlog.len() >= BACKLOG_LIMIT {
+                                let dropped = backlog.remove(0);
+                                warn!("Dispatch backlog full, drop {}", dropped.code.code());
+                            }
+                            backlog.push(job);
+                        }
+                    }
+                    // Nothing left to redeem
+                    Ok(BroadcastEvent::Fr(code)) => {
+                        backlog.retain(|job| job.code.code() != code.code())
+                    }
+                    Ok(BroadcastEvent::Exit) | Err(broadcast::error::RecvError::Closed) => break,
+                    Err(broadcast::error::RecvError::Lagged(count)) => {
+                        warn!("Dispatcher lagged, {count} codes skipped")
+                    }
+                },
+                _ = self.online.notified(), if !backlog.is_empty() => {
+                    let mut remaining = Vec::new();
+                    for job in backlog.drain(..) {
+                        remaining.extend(self.assign(&database, &sessions, job).await);
+                    }
+                    backlog = remaining;
+                }
+            }
+        }
+    }
+
+    // Returns the job back if it still needs more cookies
+    async fn assign(
+        &self,
+        database: &DatabaseHelper,
+        sessions: &SessionRegistry,
+        mut job: Unassigned,
+    ) -> Option<Unassigned> {
+        let code = &job.code;
+        // Assigning without knowing who already redeemed it would waste cookies, try again later
+        let attempted = match database.log_query_code(code.code().to_string()).await {
+            Ok(attempted) => attempted.into_iter().collect::<HashSet<_>>(),
+            Err(e) => {
+                error!("Query history of {} error: {e}", code.code());
+                return Some(job);
+            }
+        };
+        let cookies = match database.cookie_query_all(true).await {
+            Ok(cookies) => cookies,
+            Err(e) => {
+                error!("Query cookies for {} error: {e}", code.code());
+                return Some(job);
+            }
+        }
+        .into_iter()
+        .map(|cookie| cookie.id().to_lowercase())
+        // Cookie still working on it from an earlier assignment is not sent a duplicate
+        .filter(|id| {
+            !attempted.contains(id) && !job.cookies.contains(id) && !self.holds(id, code.code())
+        })
+        .collect::<Vec<_>>();
+        if cookies.is_empty() {
+            if job.cookies.is_empty() {
+                warn!("No cookie left to redeem {}", code.code());
+            }
+            return None;
+        }
+
+        let mut assigned = Vec::new();
+        for cookie in self.candidates(cookies) {
+            if job.cookies.len() >= self.config.per_code() {
+                break;
+            }
+            if sessions.dispatch(&cookie, code) {
+                self.pending
+                    .lock()
+                    .unwrap()
+                    .entry(cookie.clone())
+                    .or_default()
+                    .insert(code.code().to_string(), Instant::now());
+                job.cookies.insert(cookie.clone());
+                assigned.push(cookie);
+            }
+        }
+
+        if !assigned.is_empty() {
+            info!("Assign {} to {}", code.code(), assigned.join(", "));
+        }
+        if job.cookies.len() >= self.config.per_code() {
+            return None;
+        }
+        if assigned.is_empty() {
+            warn!("No online cookie available for {}, keep it", code.code());
+        }
+        Some(job)
+    }
+
+    // Cookies under per cookie limit, least loaded first
+    fn candidates(&self, cookies: Vec<String>) -> Vec<String> {
+        let mut pending = self.pending.lock().unwrap();
+        let timeout = self.config.job_timeout();
+        pending.retain(|_, jobs| {
+            jobs.retain(|_, assigned| assigned.elapsed() < timeout);
+            !jobs.is_empty()
+        });
+
+        let mut candidates = cookies
+            .into_iter()
+            .map(|cookie| (pending.get(&cookie).map_or(0, Jobs::len), cookie))
+            .filter(|(count, _)| *count < self.config.per_cookie())
+            .collect::<Vec<_>>();
+        candidates.sort();
+        candidates.into_iter().map(|(_, cookie)| cookie).collect()
+    }
+
+    fn holds(&self, cookie: &str, code: &str) -> bool {
+        let timeout = self.config.job_timeout();
+        self.pending
+            .lock()
+            .unwrap()
+            .get(cookie)
+            .and_then(|jobs| jobs.get(code))
+            .is_some_and(|assigned| assigned.elapsed() < timeout)
+    }
+
+    pub fn complete(&self, cookie: &str, code: &str) {
+        if let Some(jobs) = self.pending.lock().unwrap().get_mut(&cookie.to_lowercase()) {
+            jobs.remove(code);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashSet, time::Instant};
+
+    use super::{Dispatcher, Unassigned};
+    use crate::{
+        database::testing::TestDatabase,
+        types::CodeEvent,
+        web::session::{SessionCommand, SessionRegistry},
+    };
+
+    #[test]
+    fn test_candidates() {
+        let dispatcher = Dispatcher::new(
+            toml::from_str("mode = \"targeted\"\nper_code = 2\nper_cookie = 1").unwrap(),
+        );
+        assert!(dispatcher.targeted());
+        dispatcher.pending.lock().unwrap().insert(
+            "alice".to_string(),
+            [("code1".to_string(), Instant::now())].into(),
+        );
+
+        let cookies = || vec!["carol".to_string(), "alice".to_string(), "bob".to_string()];
+        assert_eq!(dispatcher.candidates(cookies()), vec!["bob", "carol"]);
+
+        dispatcher.complete("Alice", "code1");
+        assert_eq!(
+            dispatcher.candidates(cookies()),
+            vec!["alice", "bob", "carol"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_assign_later() {
+        let test = TestDatabase::new().await;
+        let database = &test.database;
+        database
+            .cookie_set(1, "alice".into(), "csrf".into(), "session".into())
+            .await
+            .unwrap();
+        let dispatcher = Dispatcher::new(toml::from_str("mode = \"targeted\"").unwrap());
+        let sessions = SessionRegistry::default();
+        let code: CodeEvent = serde_json::from_value(serde_json::json!({
+            "code": "abcde", "message_id": 1, "timestamp": 0, "seq": 1
+        }))
+        .unwrap();
+
+        // Nobody online, code is kept instead of lost
+        let job = dispatcher
+            .assign(
+                database,
+                &sessions,
+                Unassigned {
+                    code,
+                    cookies: HashSet::new(),
+                },
+            )
+            .await
+            .unwrap();
+
+        let (session, mut commands) = sessions.register("alice", "192.0.2.1".parse().unwrap());
+        session.authenticated();
+        assert!(dispatcher.assign(database, &sessions, job).await.is_none());
+        assert!(matches!(
+            commands.try_recv(),
+            Ok(SessionCommand::Job { cookie, .. }) if cookie == "alice"
+        ));
+
+        drop(session);
+        test.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_resend_pending() {
+        let test = TestDatabase::new().await;
+        let database = &test.database;
+        for cookie in ["alice", "bob"] {
+            database
+                .cookie_set(1, cookie.into(), "csrf".into(), "session".into())
+                .await
+                .unwrap();
+        }
+        let dispatcher = Dispatcher::new(toml::from_str("mode = \"targeted\"").unwrap());
+        let sessions = SessionRegistry::default();
+        let (alice, mut alice_commands) = sessions.register("alice", "192.0.2.1".parse().unwrap());
+        alice.authenticated();
+        let code: CodeEvent = serde_json::from_value(serde_json::json!({
+            "code": "abcde", "message_id": 1, "timestamp": 0, "seq": 1
+        }))
+        .unwrap();
+        let job = || Unassigned {
+            code: code.clone(),
+            cookies: HashSet::new(),
+        };
+
+        assert!(
+            dispatcher
+                .assign(database, &sessions, job())
+                .await
+                .is_none()
+        );
+        assert!(alice_commands.try_recv().is_ok());
+
+        // Resent while alice still works on it, kept for another cookie instead
+        let job = dispatcher.assign(database, &sessions, job()).await.unwrap();
+        assert!(alice_commands.try_recv().is_err());
+        let (bob, mut bob_commands) = sessions.register("bob", "192.0.2.2".parse().unwrap());
+        bob.authenticated();
+        assert!(dispatcher.assign(database, &sessions, job).await.is_none());
+        assert!(alice_commands.try_recv().is_err());
+        assert!(matches!(
+            bob_commands.try_recv(),
+            Ok(SessionCommand::Job { cookie, .. }) if cookie == "bob"
+        ));
+
+        drop((alice, bob));
+        test.close().await;
+    }
+}
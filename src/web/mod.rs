@@ -1,4 +1,9 @@
+mod admin;
+mod auth;
+mod dispatch;
 mod route;
+pub mod session;
+mod tls;
 pub mod types;
 
 pub use route::route;
@@ -1,32 +1,108 @@
-use std::sync::Arc;
+use std::{
+    convert::Infallible,
+    net::IpAddr,
+    os::unix::fs::{DirBuilderExt as _, FileTypeExt as _, PermissionsExt as _},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::{
     Extension, Json,
     extract::{
-        WebSocketUpgrade,
-        ws::{Message, WebSocket},
+        Query, WebSocketUpgrade,
+        ws::{CloseFrame, Message, WebSocket, close_code},
+    },
+    http::StatusCode,
+    response::{
+        IntoResponse,
+        sse::{Event, KeepAlive, Sse},
     },
-    response::IntoResponse,
 };
-use axum_extra::TypedHeader;
-use futures_util::SinkExt as _;
+use axum_extra::{
+    TypedHeader,
+    headers::{Authorization, authorization::Basic},
+};
+use futures_util::{SinkExt as _, Stream, StreamExt as _};
 use log::{error, info, warn};
+use teloxide::prelude::Requester as _;
 
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+    config::{Config, Web},
+    database::{BroadcastEvent, DatabaseHelper, DatabaseResult},
+    metrics::{GaugeGuard, METRICS},
+    platform::{BotType, NecessaryArg, TELEGRAM_ESCAPE_RE, mark_code_fr, submit_code},
+    types::{Auth, ClientRow, CodeEvent, RedeemReport, RedeemStatus, SubmitStatus, VStats},
+};
 
-use crate::{config::Config, database::BroadcastEvent, types::Auth};
+use super::{
+    admin,
+    auth::{AuthGuard, NonceStore},
+    dispatch::Dispatcher,
+    session::{SessionCommand, SessionHandle},
+    tls::TlsListener,
+    types::{
+        ClientIp, ClientMessage, LastEventId, PeerAddr, ServerMessage, StreamQuery, SubmitRequest,
+        SubmitResponse, SubmitResult, TrustedProxies,
+    },
+};
+
+#[derive(Clone)]
+pub struct WebContext {
+    bot: BotType,
+    arg: Arc<NecessaryArg>,
+    web: Web,
+    nonces: NonceStore,
+    guard: AuthGuard,
+    dispatcher: Dispatcher,
+}
 
-use super::types::RealIP;
+impl WebContext {
+    pub(super) fn database(&self) -> &DatabaseHelper {
+        self.arg.database()
+    }
+}
 
 pub async fn route(
     config: Config,
+    bot: BotType,
+    arg: Arc<NecessaryArg>,
     broadcast: broadcast::Receiver<BroadcastEvent>,
 ) -> anyhow::Result<()> {
     let inner_broadcast = Arc::new(broadcast.resubscribe());
-    let password = Arc::new(config.web().access_key().to_string());
+
+    let dispatcher = Dispatcher::new(config.web().dispatch().clone());
+    if dispatcher.targeted() {
+        tokio::spawn(dispatcher.clone().run(
+            arg.database().clone(),
+            arg.sessions().clone(),
+            broadcast.resubscribe(),
+        ));
+    }
 
     let router = axum::Router::new()
-        .route("/ws", axum::routing::get(handle_upgrade))
+        // Any method, WebSocket over HTTP/2 arrives as extended CONNECT
+        .route("/ws", axum::routing::any(handle_upgrade))
+        .route("/codes", axum::routing::post(handle_submit))
+        .route("/events", axum::routing::get(handle_events))
+        .route("/metrics", axum::routing::get(handle_metrics))
+        .route("/healthz", axum::routing::get(handle_healthz))
+        .route("/readyz", axum::routing::get(handle_readyz))
+        .route(
+            "/admin/users/{user}",
+            axum::routing::put(admin::handle_user_approve).delete(admin::handle_user_revoke),
+        )
+        .route(
+            "/admin/cookies",
+            axum::routing::get(admin::handle_cookie_list),
+        )
+        .route(
+            "/admin/cookies/{id}",
+            axum::routing::put(admin::handle_cookie_set).patch(admin::handle_cookie_toggle),
+        )
+        .route("/admin/history", axum::routing::get(admin::handle_history))
         .route(
             "/",
             axum::routing::get(|| async {
@@ -34,83 +110,787 @@ pub async fn route(
             }),
         )
         .layer(Extension(inner_broadcast))
-        .layer(Extension(password));
+        .layer(Extension(TrustedProxies::new(
+            config.web().trusted_proxies().to_vec(),
+        )))
+        .layer(Extension(WebContext {
+            bot,
+            arg,
+            web: config.web().clone(),
+            nonces: NonceStore::default(),
+            guard: AuthGuard::new(config.web().lockout().clone()),
+            dispatcher,
+        }));
 
-    let listener = tokio::net::TcpListener::bind(config.web().bind()).await?;
+    let router = match config
+        .web()
+        .prefix()
+        .map(|prefix| prefix.trim_matches('/'))
+        .filter(|prefix| !prefix.is_empty())
+    {
+        Some(prefix) => axum::Router::new().nest(&format!("/{prefix}"), router),
+        None => router,
+    };
 
-    axum::serve(listener, router)
-        .with_graceful_shutdown(async move {
-            let mut recv = broadcast.resubscribe();
-            while let Ok(BroadcastEvent::NewCode(_)) = recv.recv().await {}
-            tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
-        })
-        .await?;
+    let service = router.into_make_service_with_connect_info::<PeerAddr>();
+    let shutdown = async move {
+        let mut recv = broadcast.resubscribe();
+        loop {
+            match recv.recv().await {
+                Ok(BroadcastEvent::Exit) | Err(broadcast::error::RecvError::Closed) => break,
+                _ => {}
+            }
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+    };
+
+    match (
+        config.web().bind().strip_prefix("unix:"),
+        config.web().tls(),
+    ) {
+        (Some(_), Some(_)) => anyhow::bail!("TLS is not supported on unix socket"),
+        (Some(path), None) => {
+            let listener = bind_unix(path, config.web().socket_mode())?;
+            axum::serve(listener, service)
+                .with_graceful_shutdown(shutdown)
+                .await?;
+            std::fs::remove_file(path)
+                .inspect_err(|e| warn!("Remove socket {path} error: {e:?}"))
+                .ok();
+        }
+        (None, Some(tls)) => {
+            let listener = TlsListener::bind(config.web().bind(), tls).await?;
+            axum::serve(listener, service)
+                .with_graceful_shutdown(shutdown)
+                .await?;
+        }
+        (None, None) => {
+            let listener = tokio::net::TcpListener::bind(config.web().bind()).await?;
+            axum::serve(listener, service)
+                .with_graceful_shutdown(shutdown)
+                .await?;
+        }
+    }
     Ok(())
 }
 
+fn bind_unix(path: &str, mode: Option<u32>) -> anyhow::Result<tokio::net::UnixListener> {
+    let target = Path::new(path);
+    // Socket file left by previous run blocks bind, but never replace anything else or a live server
+    if let Ok(meta) = std::fs::symlink_metadata(target) {
+        if !meta.file_type().is_socket() {
+            anyhow::bail!("{path} exists and is not a socket");
+        }
+        if std::os::unix::net::UnixStream::connect(target).is_ok() {
+            anyhow::bail!("{path} is in use by another server");
+        }
+    }
+    // Bind inside a private directory and move into place, so socket never has umask permissions
+    let parent = target
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let file_name = target
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Invalid socket path {path}"))?;
+    let dir = parent.join(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let bind = || -> anyhow::Result<tokio::net::UnixListener> {
+        let staging = dir.join("socket");
+        let listener = tokio::net::UnixListener::bind(&staging)?;
+        if let Some(mode) = mode {
+            std::fs::set_permissions(&staging, std::fs::Permissions::from_mode(mode))?;
+        }
+        std::fs::rename(&staging, target)?;
+        Ok(listener)
+    };
+    let listener = bind();
+    std::fs::remove_dir_all(&dir).ok();
+    let listener = listener?;
+    info!("Listen on unix socket {path}");
+    Ok(listener)
+}
+
 pub async fn handle_upgrade(
     ws: WebSocketUpgrade,
-    TypedHeader(real_ip): TypedHeader<RealIP>,
-    Extension(broadcast): Extension<Arc<broadcast::Sender<BroadcastEvent>>>,
-    Extension(password): Extension<Arc<String>>,
+    ClientIp(ip): ClientIp,
+    Query(query): Query<StreamQuery>,
+    Extension(broadcast): Extension<Arc<broadcast::Receiver<BroadcastEvent>>>,
+    Extension(ctx): Extension<WebContext>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| async move {
-        let ip = real_ip.into_inner();
-        info!("Accept request from {ip:?}");
-        handle_code_query(socket, broadcast.subscribe(), password, &ip)
+    ws.on_upgrade(move |socket| async move {
+        info!("Accept request from {ip}");
+        handle_code_query(socket, broadcast.resubscribe(), &ctx, query.codename(), ip)
             .await
             .inspect_err(|e| error!("Handle {ip} websocket error: {e:?}"))
             .ok();
     })
 }
 
+pub async fn handle_metrics(
+    ClientIp(ip): ClientIp,
+    TypedHeader(Authorization(authorization)): TypedHeader<Authorization<Basic>>,
+    Extension(ctx): Extension<WebContext>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Labels name agents, so only admins may scrape
+    check_admin(&ctx, ip, &authorization).await?;
+    Ok((
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4",
+        )],
+        METRICS.render(ctx.database().queue_depth()),
+    ))
+}
+
+// Round trip a cheap query through database actor, None if actor is gone or stuck
+async fn probe_database(database: &DatabaseHelper) -> Option<Option<VStats>> {
+    tokio::time::timeout(Duration::from_secs(3), database.v_query())
+        .await
+        .ok()?
+        .ok()
+}
+
+pub async fn handle_healthz(Extension(ctx): Extension<WebContext>) -> impl IntoResponse {
+    let database = probe_database(ctx.database()).await.is_some();
+    (
+        if database {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+        Json(serde_json::json!({"database": database})),
+    )
+}
+
+pub async fn handle_readyz(Extension(ctx): Extension<WebContext>) -> impl IntoResponse {
+    let probe = probe_database(ctx.database()).await;
+    let database = probe.is_some();
+    let dispatcher = ctx.arg.dispatching();
+    let intel_v = probe.flatten().map(|v| {
+        serde_json::json!({
+            "v": v.v(),
+            "last": v.last(),
+            "age": kstool::time::get_current_second().saturating_sub(v.last()),
+        })
+    });
+    (
+        if database && dispatcher {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+        Json(serde_json::json!({
+            "database": database,
+            "dispatcher": dispatcher,
+            "intel_v": intel_v,
+        })),
+    )
+}
+
+pub async fn handle_submit(
+    ClientIp(ip): ClientIp,
+    TypedHeader(Authorization(authorization)): TypedHeader<Authorization<Basic>>,
+    Extension(ctx): Extension<WebContext>,
+    Json(request): Json<SubmitRequest>,
+) -> Result<Json<SubmitResponse>, StatusCode> {
+    let codename = authorization.username();
+    check_basic_auth(&ctx, ip, &authorization).await?;
+
+    // One failed code must not hide results of codes already posted
+    let mut results = Vec::with_capacity(request.codes().len());
+    for code in request.codes() {
+        let status = submit_code(&ctx.bot, &ctx.arg, code)
+            .await
+            .inspect_err(|e| {
+                METRICS.telegram_error(e);
+                error!("Submit {code} from {codename} error: {e:?}")
+            })
+            .unwrap_or(SubmitStatus::Failed);
+        info!("{codename} submit {code}: {status:?}");
+        results.push(SubmitResult::new(code.clone(), status));
+    }
+    Ok(Json(results.into()))
+}
+
+pub async fn handle_events(
+    ClientIp(ip): ClientIp,
+    TypedHeader(Authorization(authorization)): TypedHeader<Authorization<Basic>>,
+    last_event_id: Option<TypedHeader<LastEventId>>,
+    Extension(broadcast): Extension<Arc<broadcast::Receiver<BroadcastEvent>>>,
+    Extension(ctx): Extension<WebContext>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    let codename = authorization.username();
+    check_basic_auth(&ctx, ip, &authorization).await?;
+    info!("Accept event stream from {codename}({ip})");
+
+    // Subscribe before querying database, so nothing is missed between replay and live events
+    let broadcast = broadcast.resubscribe();
+    // Jobs from dispatcher replace broadcast codes in targeted mode
+    let targeted = ctx.dispatcher.targeted();
+    let replay = match last_event_id {
+        Some(_) if targeted => {
+            info!("Skip replay to {codename} in targeted mode");
+            Vec::new()
+        }
+        Some(TypedHeader(id)) => ctx
+            .database()
+            .code_query_since(Some(id.into_inner()), None)
+            .await
+            // Empty replay would tell the client it has caught up
+            .map_err(|e| {
+                error!("Replay codes to {codename} error: {e}");
+                StatusCode::SERVICE_UNAVAILABLE
+            })?,
+        None => Vec::new(),
+    };
+    let last_seq = replay.last().map(|row| row.seq()).unwrap_or_default();
+
+    // Registered like a WebSocket session, so it can be kicked and receives jobs
+    let (session, commands) = ctx.arg.sessions().register(codename, ip);
+    session.authenticated();
+    session.delivered(replay.len());
+    if targeted {
+        ctx.dispatcher.online();
+    }
+    let state = EventStream {
+        codename: codename.to_string(),
+        broadcast,
+        commands,
+        session,
+        _connected: GaugeGuard::new(METRICS.ws_connected()),
+        _authenticated: GaugeGuard::new(METRICS.ws_authenticated()),
+        closed: false,
+    };
+
+    let replay = futures_util::stream::iter(
+        replay
+            .into_iter()
+            .map(|row| Ok(sse_event(&ServerMessage::Code(&CodeEvent::from(row))))),
+    );
+    let live = futures_util::stream::unfold(state, move |mut state| async move {
+        if state.closed {
+            return None;
+        }
+        loop {
+            let event = tokio::select! {
+                Some(command) = state.commands.recv() => match command {
+                    SessionCommand::Kick => {
+                        info!("Kick event stream of {}", state.codename);
+                        state.closed = true;
+                        sse_event(&ServerMessage::Close)
+                    }
+                    SessionCommand::Job { cookie, code } => {
+                        state.session.delivered(1);
+                        sse_event(&ServerMessage::Job { cookie: &cookie, code: &code })
+                    }
+                },
+                event = state.broadcast.recv() => match event {
+                    Ok(BroadcastEvent::NewCode(_) | BroadcastEvent::Resend(_)) if targeted => continue,
+                    Ok(BroadcastEvent::NewCode(code)) => {
+                        if code.seq() <= last_seq {
+                            continue;
+                        }
+                        state.session.delivered(1);
+                        sse_event(&ServerMessage::Code(&code))
+                    }
+                    Ok(BroadcastEvent::Resend(code)) => {
+                        state.session.delivered(1);
+                        sse_event(&ServerMessage::Resend(&code))
+                    }
+                    Ok(BroadcastEvent::Fr(_)) => continue,
+                    Ok(BroadcastEvent::Exit) => {
+                        state.closed = true;
+                        sse_event(&ServerMessage::Close)
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("Event stream lagged {n} events");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                },
+            };
+            return Some((Ok(event), state));
+        }
+    });
+
+    Ok(Sse::new(replay.chain(live)).keep_alive(KeepAlive::default()))
+}
+
+// Live part of an event stream, session is unregistered when client goes away
+struct EventStream {
+    codename: String,
+    broadcast: broadcast::Receiver<BroadcastEvent>,
+    commands: mpsc::UnboundedReceiver<SessionCommand>,
+    session: SessionHandle,
+    _connected: GaugeGuard,
+    _authenticated: GaugeGuard,
+    closed: bool,
+}
+
+fn sse_event(message: &ServerMessage) -> Event {
+    let event = Event::default()
+        .event(message.kind())
+        .data(message.to_json());
+    // Only new codes carry an id, so resend events never rewind Last-Event-ID
+    match message {
+        ServerMessage::Code(code) => event.id(code.seq().to_string()),
+        _ => event,
+    }
+}
+
+async fn check_basic_auth(
+    ctx: &WebContext,
+    ip: IpAddr,
+    authorization: &Basic,
+) -> Result<(), StatusCode> {
+    let codename = authorization.username();
+    if let Some(remaining) = ctx.guard.locked(ip, codename) {
+        warn!(
+            "ID: {codename} from {ip} rejected, locked out for {}s",
+            remaining.as_secs()
+        );
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+    // Database failure says nothing about the password, so it is not a failed attempt
+    let client = ctx
+        .database()
+        .client_query(codename.to_string())
+        .await
+        .map_err(|e| {
+            error!("Query client {codename} error: {e}");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+    if client.is_some_and(|client| client.verify_token(authorization.password())) {
+        ctx.guard.success(ip, codename);
+        return Ok(());
+    }
+    warn!("ID: {codename} from {ip} password check failed");
+    record_failure(ctx, ip, codename).await;
+    Err(StatusCode::UNAUTHORIZED)
+}
+
+// Basic auth of a client listed in `admin` of web config
+pub(super) async fn check_admin(
+    ctx: &WebContext,
+    ip: IpAddr,
+    authorization: &Basic,
+) -> Result<(), StatusCode> {
+    check_basic_auth(ctx, ip, authorization).await?;
+    let codename = authorization.username();
+    if !ctx.web.admin().iter().any(|admin| admin == codename) {
+        warn!("ID: {codename} from {ip} is not admin");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+async fn record_failure(ctx: &WebContext, ip: IpAddr, codename: &str) {
+    for key in ctx.guard.failure(ip, codename) {
+        let text = format!(
+            "Authentication failed {} times for {key}, last attempt as {codename} from {ip}",
+            ctx.web.lockout().alert().unwrap_or_default()
+        );
+        for admin in ctx.arg.admin() {
+            ctx.bot
+                .send_message(*admin, TELEGRAM_ESCAPE_RE.replace_all(&text, "\\$1"))
+                .await
+                .map_err(anyhow::Error::from)
+                .inspect_err(|e| {
+                    METRICS.telegram_error(e);
+                    error!("Send alert to {admin} error: {e:?}")
+                })
+                .ok();
+        }
+    }
+}
+
+async fn close_policy(socket: &mut WebSocket, reason: &'static str) {
+    socket
+        .send(Message::Close(Some(CloseFrame {
+            code: close_code::POLICY,
+            reason: reason.into(),
+        })))
+        .await
+        .ok();
+}
+
+async fn close_unavailable(socket: &mut WebSocket) {
+    socket
+        .send(Message::Close(Some(CloseFrame {
+            code: close_code::AGAIN,
+            reason: "database unavailable".into(),
+        })))
+        .await
+        .ok();
+}
+
+async fn check_auth(
+    database: &DatabaseHelper,
+    nonces: &NonceStore,
+    auth: &Auth,
+    codename: &str,
+    nonce: &str,
+) -> DatabaseResult<bool> {
+    if auth.codename() != codename || auth.nonce() != nonce || !nonces.consume(nonce) {
+        return Ok(false);
+    }
+    Ok(database
+        .client_query(codename.to_string())
+        .await?
+        .is_some_and(|client| auth.check(&client)))
+}
+
+async fn send_challenge(
+    socket: &mut WebSocket,
+    nonces: &NonceStore,
+    setting: &str,
+) -> anyhow::Result<String> {
+    let nonce = nonces.issue();
+    socket
+        .send(Message::Text(
+            ServerMessage::Challenge {
+                nonce: &nonce,
+                setting,
+            }
+            .to_json()
+            .into(),
+        ))
+        .await?;
+    Ok(nonce)
+}
+
+async fn handle_report(
+    ctx: &WebContext,
+    reporter: &str,
+    report: RedeemReport,
+) -> anyhow::Result<()> {
+    // Session only serves the cookie named as its codename in targeted mode, reporting for another
+    // one would clear its jobs and make dispatcher skip it
+    if ctx.dispatcher.targeted() && !report.codename().eq_ignore_ascii_case(reporter) {
+        warn!(
+            "{reporter} report {} as {}, reject",
+            report.code(),
+            report.codename()
+        );
+        return Ok(());
+    }
+    ctx.dispatcher.complete(reporter, report.code());
+    let database = ctx.database();
+    database
+        .log_insert(
+            report.codename().to_lowercase(),
+            report.code().to_string(),
+            report.status().error(),
+            report.reward(),
+            Some(reporter.to_string()),
+        )
+        .await?;
+
+    let threshold = ctx.web.fr_threshold();
+    if threshold == 0 || report.status() != RedeemStatus::FullyRedeemed {
+        return Ok(());
+    }
+    let count = database
+        .log_count(
+            report.code().to_string(),
+            report.status().error().unwrap_or_default(),
+        )
+        .await?;
+    if count >= threshold
+        && database
+            .code_query(report.code().to_string())
+            .await?
+            .is_some_and(|code| !code.is_fr())
+    {
+        info!(
+            "{} reported fully redeemed by {count} clients, mark as FR",
+            report.code()
+        );
+        // Editing the Telegram message may be slow, never hold up this client's stream
+        let (bot, arg, code) = (ctx.bot.clone(), ctx.arg.clone(), report.code().to_string());
+        tokio::spawn(async move {
+            if let Err(e) = mark_code_fr(&bot, &arg, &code).await {
+                METRICS.telegram_error(&e);
+                error!("Mark {code} as FR error: {e:?}");
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn replay_codes(
+    socket: &mut WebSocket,
+    database: &DatabaseHelper,
+    session: &SessionHandle,
+    seq: Option<i64>,
+    timestamp: Option<i64>,
+) -> anyhow::Result<Option<i64>> {
+    let mut last_seq = None;
+    for row in database.code_query_since(seq, timestamp).await? {
+        let code = CodeEvent::from(row);
+        socket
+            .send(Message::Text(ServerMessage::Code(&code).to_json().into()))
+            .await?;
+        session.delivered(1);
+        last_seq = Some(code.seq());
+    }
+    Ok(last_seq)
+}
+
 pub async fn handle_code_query(
     mut socket: WebSocket,
     mut broadcast: broadcast::Receiver<BroadcastEvent>,
-    password: Arc<String>,
-    ip: &str,
+    ctx: &WebContext,
+    codename: &str,
+    ip: IpAddr,
 ) -> anyhow::Result<()> {
+    let database = ctx.database();
+    let nonces = &ctx.nonces;
+    let _connected = GaugeGuard::new(METRICS.ws_connected());
+    let mut authenticated = None;
     let mut is_register = false;
+    let mut last_seq = 0;
+
+    if let Some(remaining) = ctx.guard.locked(ip, codename) {
+        warn!(
+            "ID: {codename} from {ip} rejected, locked out for {}s",
+            remaining.as_secs()
+        );
+        close_policy(&mut socket, "locked out").await;
+        return Ok(());
+    }
+
+    let (session, mut commands) = ctx.arg.sessions().register(codename, ip);
+    // Jobs from dispatcher replace broadcast codes in targeted mode
+    let targeted = ctx.dispatcher.targeted();
+
+    let setting = match database.client_query(codename.to_string()).await {
+        Ok(Some(client)) => Some(client.setting().to_string()),
+        Ok(None) => ClientRow::fake_setting(codename),
+        Err(e) => {
+            error!("Query client {codename} error: {e}");
+            close_unavailable(&mut socket).await;
+            return Ok(());
+        }
+    }
+    .unwrap_or_default();
+    let mut nonce = send_challenge(&mut socket, nonces, &setting).await?;
+    let deadline = tokio::time::sleep(ctx.web.auth_timeout());
+    tokio::pin!(deadline);
+    let heartbeat = ctx.web.heartbeat();
+    let period = heartbeat.unwrap_or(Duration::from_secs(3600));
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let mut last_seen = Instant::now();
+    let mut last_recorded = last_seen;
 
     loop {
         tokio::select! {
+            _ = &mut deadline, if !is_register => {
+                warn!("{ip} did not authenticate as {codename} in time");
+                close_policy(&mut socket, "authentication timeout").await;
+                return Ok(());
+            }
+            Some(command) = commands.recv() => {
+                match command {
+                    SessionCommand::Kick => {
+                        info!("Kick {codename} from {ip}");
+                        close_policy(&mut socket, "kicked").await;
+                        break;
+                    }
+                    SessionCommand::Job { cookie, code } => {
+                        socket
+                            .send(Message::Text(
+                                ServerMessage::Job { cookie: &cookie, code: &code }
+                                    .to_json()
+                                    .into(),
+                            ))
+                            .await?;
+                        session.delivered(1);
+                    }
+                }
+            }
+            _ = ticker.tick(), if heartbeat.is_some() => {
+                // Allow one missed pong before treating connection as dead
+                if last_seen.elapsed() > period * 2 {
+                    warn!("{ip} missed heartbeat, close connection");
+                    break;
+                }
+                if is_register && last_seen > last_recorded {
+                    record_seen(database, codename, last_seen).await;
+                    last_recorded = last_seen;
+                }
+                socket.send(Message::Ping(Default::default())).await?;
+            }
             Ok(event) = broadcast.recv() => {
                 if !is_register {
                     continue;
                 }
                 match event {
+                    BroadcastEvent::NewCode(_) | BroadcastEvent::Resend(_) if targeted => {}
+                    BroadcastEvent::Fr(_) => {}
                     BroadcastEvent::NewCode(code) => {
-                        socket.send(Message::Text(code.into())).await?;
+                        if code.seq() <= last_seq {
+                            continue;
+                        }
+                        socket
+                            .send(Message::Text(ServerMessage::Code(&code).to_json().into()))
+                            .await?;
+                        session.delivered(1);
+                    }
+                    BroadcastEvent::Resend(code) => {
+                        socket
+                            .send(Message::Text(ServerMessage::Resend(&code).to_json().into()))
+                            .await?;
+                        session.delivered(1);
                     }
                     BroadcastEvent::Exit => {
-                        socket.send(Message::Text("close".into())).await.ok();
+                        socket
+                            .send(Message::Text(ServerMessage::Close.to_json().into()))
+                            .await
+                            .ok();
                         break;
                     }
                 }
             }
             Some(message) = socket.recv() => {
                 if let Ok(message) = message {
+                    last_seen = Instant::now();
+                    match message {
+                        Message::Ping(_) | Message::Pong(_) => continue,
+                        Message::Close(_) => break,
+                        _ => {}
+                    }
                     if let Ok(text) = message.to_text() {
-                        if text.eq("close") {
-                            break;
-                        }
-                        if let Ok(header) = Auth::try_from(text) {
-                            if header.check(&password) {
-                                is_register = true;
-                            } else {
-                                warn!("ID: {} password check failed", header.codename());
+                        match ClientMessage::try_from(text) {
+                            Ok(ClientMessage::Close) => break,
+                            Ok(ClientMessage::Result(report)) => {
+                                if !is_register {
+                                    continue;
+                                }
+                                info!(
+                                    "{codename} report {} {}: {:?}",
+                                    report.codename(),
+                                    report.code(),
+                                    report.status()
+                                );
+                                session.ack();
+                                handle_report(ctx, codename, report)
+                                    .await
+                                    .inspect_err(|e| error!("Handle report error: {e:?}"))
+                                    .ok();
+                            }
+                            Ok(ClientMessage::Auth(header)) => {
+                                if ctx.guard.locked(ip, codename).is_some() {
+                                    warn!("ID: {codename} from {ip} rejected, locked out");
+                                    close_policy(&mut socket, "locked out").await;
+                                    return Ok(());
+                                }
+                                let verified =
+                                    check_auth(database, nonces, &header, codename, &nonce).await;
+                                // Database failure is not a failed attempt, close without counting it
+                                let verified = match verified {
+                                    Ok(verified) => verified,
+                                    Err(e) => {
+                                        error!("Verify {codename} from {ip} error: {e}");
+                                        close_unavailable(&mut socket).await;
+                                        return Ok(());
+                                    }
+                                };
+                                if verified {
+                                    ctx.guard.success(ip, codename);
+                                    record_seen(database, codename, last_seen).await;
+                                    session.authenticated();
+                                    is_register = true;
+                                    authenticated
+                                        .get_or_insert_with(|| GaugeGuard::new(METRICS.ws_authenticated()));
+                                    // Codes still short of cookies are handed out by dispatcher in targeted mode
+                                    if targeted {
+                                        ctx.dispatcher.online();
+                                        if header.resume().is_some() {
+                                            info!("Skip replay to {codename} in targeted mode");
+                                        }
+                                    } else if let Some((seq, timestamp)) = header.resume() {
+                                        last_seq =
+                                            replay_codes(&mut socket, database, &session, seq, timestamp)
+                                                .await?
+                                                .unwrap_or(last_seq);
+                                        info!(
+                                            "Replay codes to {} until seq {last_seq}",
+                                            header.codename()
+                                        );
+                                    }
+                                } else {
+                                    warn!(
+                                        "ID: {} from {ip} password check failed",
+                                        header.codename()
+                                    );
+                                    record_failure(ctx, ip, codename).await;
+                                    if ctx.guard.locked(ip, codename).is_some() {
+                                        close_policy(&mut socket, "locked out").await;
+                                        return Ok(());
+                                    }
+                                    nonce = send_challenge(&mut socket, nonces, &setting).await?;
+                                }
+                            }
+                            Err(e) => {
+                                warn!("Skip unrecognized message from {ip}: {e}");
                             }
                         }
                     } else {
                         warn!("Skip unreadable bytes: {message:?}");
                     }
                 } else {
-                    return Ok(());
+                    break;
                 }
             }
         }
     }
+    if is_register {
+        record_seen(database, codename, last_seen).await;
+    }
     socket.close().await.ok();
     info!("Disconnect from: {ip}");
     Ok(())
 }
+
+async fn record_seen(database: &DatabaseHelper, codename: &str, last_seen: Instant) {
+    let timestamp =
+        kstool::time::get_current_second() as i64 - last_seen.elapsed().as_secs() as i64;
+    if let Err(e) = database.client_seen(codename.to_string(), timestamp).await {
+        warn!("Record {codename} last seen error: {e}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use super::bind_unix;
+
+    #[tokio::test]
+    async fn test_bind_unix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("web.sock");
+        let path = path.to_str().unwrap();
+
+        let listener = bind_unix(path, Some(0o600)).unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Live server is left alone
+        assert!(bind_unix(path, None).is_err());
+
+        // Stale socket is replaced
+        drop(listener);
+        drop(bind_unix(path, None).unwrap());
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        assert!(bind_unix(file.to_str().unwrap(), None).is_err());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    platform::TELEGRAM_ESCAPE_RE,
+    types::{CodeEvent, HistoryRow},
+};
+
+#[derive(Clone, Debug)]
+pub enum SessionCommand {
+    Kick,
+    // Redeem code with the cookie of given agent
+    Job { cookie: String, code: CodeEvent },
+}
+
+#[derive(Clone, Debug)]
+pub struct Session {
+    id: u64,
+    codename: String,
+    ip: IpAddr,
+    connected: i64,
+    delivered: u64,
+    last_ack: Option<i64>,
+    authenticated: bool,
+}
+
+impl Session {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn codename(&self) -> &str {
+        &self.codename
+    }
+}
+
+impl std::fmt::Display for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let escape = |timestamp: i64| {
+            TELEGRAM_ESCAPE_RE
+                .replace_all(&HistoryRow::timestamp_to_string(timestamp), "\\$1")
+                .to_string()
+        };
+        write!(
+            f,
+            "\\#{} `{}` from `{}` since {}, delivered {}, last ack {}",
+            self.id,
+            self.codename,
+            self.ip,
+            escape(self.connected),
+            self.delivered,
+            self.last_ack.map(escape).unwrap_or_else(|| "never".into())
+        )
+    }
+}
+
+struct Entry {
+    session: Session,
+    sender: mpsc::UnboundedSender<SessionCommand>,
+    kicked: bool,
+}
+
+// Active stream sessions, shared by web server and bot
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    inner: Arc<Mutex<BTreeMap<u64, Entry>>>,
+    next: Arc<AtomicU64>,
+}
+
+impl std::fmt::Debug for SessionRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionRegistry")
+            .field("sessions", &self.inner.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl SessionRegistry {
+    pub fn register(
+        &self,
+        codename: &str,
+        ip: IpAddr,
+    ) -> (SessionHandle, mpsc::UnboundedReceiver<SessionCommand>) {
+        let id = self.next.fetch_add(1, Ordering::Relaxed) + 1;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.inner.lock().unwrap().insert(
+            id,
+            Entry {
+                session: Session {
+                    id,
+                    codename: codename.to_string(),
+                    ip,
+                    connected: kstool::time::get_current_second() as i64,
+                    delivered: 0,
+                    last_ack: None,
+                    authenticated: false,
+                },
+                sender,
+                kicked: false,
+            },
+        );
+        (
+            SessionHandle {
+                id,
+                registry: self.clone(),
+            },
+            receiver,
+        )
+    }
+
+    // Authenticated sessions only, unauthenticated sockets are not worth showing
+    pub fn list(&self) -> Vec<Session> {
+        self.inner
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|entry| entry.session.authenticated)
+            .map(|entry| entry.session.clone())
+            .collect()
+    }
+
+    pub fn kick(&self, id: u64) -> Option<Session> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.get_mut(&id).filter(|entry| !entry.kicked)?;
+        entry.sender.send(SessionCommand::Kick).ok()?;
+        entry.kicked = true;
+        Some(entry.session.clone())
+    }
+
+    // Kick every session connected as this codename, returns how many were kicked
+    pub fn kick_codename(&self, codename: &str) -> usize {
+        let mut kicked = 0;
+        for entry in self.inner.lock().unwrap().values_mut() {
+            if !entry.kicked
+                && entry.session.codename == codename
+                && entry.sender.send(SessionCommand::Kick).is_ok()
+            {
+                entry.kicked = true;
+                kicked += 1;
+            }
+        }
+        kicked
+    }
+
+    // Send job to the least loaded authenticated session of this agent, false if none online.
+    // Cookies are not owned by clients, a session serves exactly the cookie named as its codename
+    pub fn dispatch(&self, cookie: &str, code: &CodeEvent) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(entry) = inner
+            .values_mut()
+            .filter(|entry| {
+                entry.session.authenticated
+                    && !entry.kicked
+                    && entry.session.codename.eq_ignore_ascii_case(cookie)
+            })
+            .min_by_key(|entry| entry.session.delivered)
+        else {
+            return false;
+        };
+        entry
+            .sender
+            .send(SessionCommand::Job {
+                cookie: cookie.to_string(),
+                code: code.clone(),
+            })
+            .is_ok()
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut Session)) {
+        if let Some(entry) = self.inner.lock().unwrap().get_mut(&id) {
+            f(&mut entry.session);
+        }
+    }
+}
+
+// Removes the session from registry when connection handler ends
+pub struct SessionHandle {
+    id: u64,
+    registry: SessionRegistry,
+}
+
+impl SessionHandle {
+    pub fn authenticated(&self) {
+        self.registry
+            .update(self.id, |session| session.authenticated = true);
+    }
+
+    pub fn delivered(&self, count: usize) {
+        self.registry
+            .update(self.id, |session| session.delivered += count as u64);
+    }
+
+    pub fn ack(&self) {
+        self.registry.update(self.id, |session| {
+            session.last_ack = Some(kstool::time::get_current_second() as i64)
+        });
+    }
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        self.registry.inner.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SessionCommand, SessionRegistry};
+    use crate::types::CodeEvent;
+
+    #[test]
+    fn test_registry() {
+        let registry = SessionRegistry::default();
+        let (handle, mut commands) = registry.register("alice", "192.0.2.1".parse().unwrap());
+        assert!(registry.list().is_empty());
+
+        handle.authenticated();
+        handle.delivered(2);
+        let sessions = registry.list();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].codename(), "alice");
+        assert_eq!(sessions[0].delivered, 2);
+
+        assert!(commands.try_recv().is_err());
+        let code: CodeEvent = serde_json::from_value(serde_json::json!({
+            "code": "abcde", "message_id": 1, "timestamp": 0, "seq": 1
+        }))
+        .unwrap();
+        assert!(!registry.dispatch("bob", &code));
+        assert!(registry.dispatch("Alice", &code));
+        assert!(matches!(
+            commands.try_recv(),
+            Ok(SessionCommand::Job { cookie, .. }) if cookie == "Alice"
+        ));
+
+        assert!(registry.kick(sessions[0].id()).is_some());
+        assert!(matches!(commands.try_recv(), Ok(SessionCommand::Kick)));
+        assert!(registry.kick(sessions[0].id()).is_none());
+
+        let (_other, mut other_commands) = registry.register("alice", "192.0.2.2".parse().unwrap());
+        assert_eq!(registry.kick_codename("bob"), 0);
+        assert_eq!(registry.kick_codename("alice"), 1);
+        assert!(matches!(
+            other_commands.try_recv(),
+            Ok(SessionCommand::Kick)
+        ));
+        assert_eq!(registry.kick_codename("alice"), 0);
+
+        drop(handle);
+        assert!(registry.list().is_empty());
+    }
+}
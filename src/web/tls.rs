@@ -0,0 +1,184 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, RwLock, Weak},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::anyhow;
+use axum::serve::Listener;
+use log::{debug, error, info, warn};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        crypto::{CryptoProvider, ring},
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+    },
+    server::TlsStream,
+};
+
+use crate::config::Tls;
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct CertResolver {
+    tls: Tls,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<(Arc<CertifiedKey>, Option<SystemTime>)>,
+}
+
+impl CertResolver {
+    fn new(tls: Tls, provider: Arc<CryptoProvider>) -> anyhow::Result<Self> {
+        let modified = Self::modified(&tls);
+        let key = Self::load(&tls, &provider)?;
+        Ok(Self {
+            tls,
+            provider,
+            current: RwLock::new((Arc::new(key), modified)),
+        })
+    }
+
+    fn load(tls: &Tls, provider: &CryptoProvider) -> anyhow::Result<CertifiedKey> {
+        let certs = CertificateDer::pem_file_iter(tls.cert())
+            .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| anyhow!("Read certificate {} error: {e:?}", tls.cert()))?;
+        let key = PrivateKeyDer::from_pem_file(tls.key())
+            .map_err(|e| anyhow!("Read private key {} error: {e:?}", tls.key()))?;
+        Ok(CertifiedKey::from_der(certs, key, provider)?)
+    }
+
+    // Latest modification time of certificate and key, None if either is unreadable
+    fn modified(tls: &Tls) -> Option<SystemTime> {
+        let cert = std::fs::metadata(tls.cert())
+            .and_then(|m| m.modified())
+            .ok()?;
+        let key = std::fs::metadata(tls.key())
+            .and_then(|m| m.modified())
+            .ok()?;
+        Some(cert.max(key))
+    }
+
+    fn reload(&self) {
+        let modified = Self::modified(&self.tls);
+        if modified.is_none() || modified == self.current.read().unwrap().1 {
+            return;
+        }
+        match Self::load(&self.tls, &self.provider) {
+            Ok(key) => {
+                *self.current.write().unwrap() = (Arc::new(key), modified);
+                info!("Reloaded TLS certificate from {}", self.tls.cert());
+            }
+            // Files may be half written by renewal, keep serving previous one and retry later
+            Err(e) => warn!("Reload TLS certificate error, keep previous one: {e:?}"),
+        }
+    }
+
+    async fn watch(resolver: Weak<Self>) {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let Some(resolver) = resolver.upgrade() else {
+                break;
+            };
+            resolver.reload();
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().0.clone())
+    }
+}
+
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    receiver: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+    pub async fn bind(addr: &str, tls: &Tls) -> anyhow::Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let resolver = Arc::new(CertResolver::new(tls.clone(), provider.clone())?);
+        tokio::spawn(CertResolver::watch(Arc::downgrade(&resolver)));
+
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (sender, receiver) = mpsc::channel(64);
+        tokio::spawn(Self::accept_loop(
+            listener,
+            TlsAcceptor::from(Arc::new(config)),
+            sender,
+        ));
+
+        Ok(Self {
+            local_addr,
+            receiver,
+        })
+    }
+
+    // Handshakes run in their own tasks so a stalled client can't block the accept loop
+    async fn accept_loop(
+        listener: TcpListener,
+        acceptor: TlsAcceptor,
+        sender: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>,
+    ) {
+        loop {
+            let (stream, peer) = tokio::select! {
+                _ = sender.closed() => break,
+                ret = listener.accept() => match ret {
+                    Ok(ret) => ret,
+                    Err(e) => {
+                        error!("Accept TCP connection error: {e:?}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                },
+            };
+
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        sender.send((stream, peer)).await.ok();
+                    }
+                    Ok(Err(e)) => debug!("TLS handshake with {peer} error: {e:?}"),
+                    Err(_) => debug!("TLS handshake with {peer} timeout"),
+                }
+            });
+        }
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.receiver.recv().await {
+            Some(ret) => ret,
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
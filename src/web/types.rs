@@ -1,7 +1,23 @@
-use std::sync::LazyLock;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, LazyLock},
+};
 
-use axum::http::HeaderValue;
-use axum_extra::headers::{self, Header};
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, connect_info::Connected},
+    http::{HeaderMap, HeaderValue, StatusCode, request::Parts},
+    serve::IncomingStream,
+};
+use axum_extra::headers::{self, Header, HeaderMapExt};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, UnixListener};
+
+use crate::types::{AccessLevel, Auth, CodeEvent, RedeemReport, SubmitStatus};
+
+use super::tls::TlsListener;
+
+pub const PROTOCOL_VERSION: u32 = 1;
 
 static HEADER_REAL_IP_NAME: LazyLock<axum::http::HeaderName> =
     LazyLock::new(|| "X-Real-IP".parse().unwrap());
@@ -37,3 +53,458 @@ impl RealIP {
         self.0
     }
 }
+
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(Arc<Vec<IpNet>>);
+
+impl TrustedProxies {
+    pub fn new(proxies: Vec<IpNet>) -> Self {
+        Self(Arc::new(proxies))
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+
+    // Walk the forwarded chain from nearest hop, first untrusted address is the client
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.contains(&peer) {
+            return peer;
+        }
+        let chain = Self::forwarded_chain(headers);
+        chain
+            .iter()
+            .rev()
+            .find(|ip| !self.contains(ip))
+            .or(chain.first())
+            .copied()
+            .unwrap_or(peer)
+    }
+
+    fn forwarded_chain(headers: &HeaderMap) -> Vec<IpAddr> {
+        let forwarded = headers
+            .get_all(axum::http::header::FORWARDED)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for")
+                        .then(|| parse_node(value))
+                        .flatten()
+                })
+            })
+            .collect::<Vec<_>>();
+        if !forwarded.is_empty() {
+            return forwarded;
+        }
+
+        let forwarded_for = headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(parse_node)
+            .collect::<Vec<_>>();
+        if !forwarded_for.is_empty() {
+            return forwarded_for;
+        }
+
+        headers
+            .typed_get::<RealIP>()
+            .and_then(|ip| parse_node(&ip.into_inner()))
+            .into_iter()
+            .collect()
+    }
+}
+
+// Accept `1.2.3.4`, `1.2.3.4:80`, `"[::1]:80"` and `::1`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|s| s.split_once(']'))
+        .and_then(|(ip, _)| ip.parse().ok())
+}
+
+// Socket peer of any listener the web server supports
+#[derive(Clone, Copy, Debug)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl PeerAddr {
+    // Unix socket peers are on the same host, treat them as loopback
+    pub fn ip(&self) -> IpAddr {
+        match self {
+            Self::Tcp(addr) => addr.ip(),
+            Self::Unix => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self::Tcp(*stream.remote_addr())
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        Self::Tcp(*stream.remote_addr())
+    }
+}
+
+impl Connected<IncomingStream<'_, UnixListener>> for PeerAddr {
+    fn connect_info(_stream: IncomingStream<'_, UnixListener>) -> Self {
+        Self::Unix
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<PeerAddr>>()
+            .map(|info| info.0.ip())
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        let trusted = parts
+            .extensions
+            .get::<TrustedProxies>()
+            .cloned()
+            .unwrap_or_default();
+        Ok(Self(trusted.resolve(peer, &parts.headers)))
+    }
+}
+
+static HEADER_LAST_EVENT_ID_NAME: LazyLock<axum::http::HeaderName> =
+    LazyLock::new(|| "Last-Event-ID".parse().unwrap());
+
+pub struct LastEventId(i64);
+
+impl Header for LastEventId {
+    fn name() -> &'static axum::http::HeaderName {
+        &HEADER_LAST_EVENT_ID_NAME
+    }
+
+    fn decode<'i, I>(values: &mut I) -> Result<Self, axum_extra::headers::Error>
+    where
+        Self: Sized,
+        I: Iterator<Item = &'i axum::http::HeaderValue>,
+    {
+        let value = values.next().ok_or_else(headers::Error::invalid)?;
+        value
+            .to_str()
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .map(Self)
+            .ok_or_else(headers::Error::invalid)
+    }
+
+    fn encode<E: Extend<axum::http::HeaderValue>>(&self, values: &mut E) {
+        values.extend(std::iter::once(HeaderValue::from(self.0)))
+    }
+}
+
+impl LastEventId {
+    pub fn into_inner(self) -> i64 {
+        self.0
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SubmitRequest {
+    codes: Vec<String>,
+}
+
+impl SubmitRequest {
+    pub fn codes(&self) -> &[String] {
+        &self.codes
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SubmitResult {
+    code: String,
+    status: SubmitStatus,
+}
+
+impl SubmitResult {
+    pub fn new(code: String, status: SubmitStatus) -> Self {
+        Self { code, status }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SubmitResponse {
+    results: Vec<SubmitResult>,
+}
+
+impl From<Vec<SubmitResult>> for SubmitResponse {
+    fn from(results: Vec<SubmitResult>) -> Self {
+        Self { results }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApproveRequest {
+    level: AccessLevel,
+}
+
+impl ApproveRequest {
+    pub fn level(&self) -> AccessLevel {
+        self.level
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CookieRequest {
+    user: i64,
+    csrf_token: String,
+    session_id: String,
+}
+
+impl CookieRequest {
+    pub fn user(&self) -> i64 {
+        self.user
+    }
+
+    pub fn csrf_token(&self) -> &str {
+        &self.csrf_token
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ToggleRequest {
+    enabled: bool,
+}
+
+impl ToggleRequest {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CookieFilter {
+    #[serde(default)]
+    enabled: bool,
+}
+
+impl CookieFilter {
+    pub fn enabled_only(&self) -> bool {
+        self.enabled
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct HistoryQuery {
+    #[serde(default)]
+    id: String,
+}
+
+impl HistoryQuery {
+    // Empty id queries latest entries of all agents
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct StreamQuery {
+    codename: String,
+}
+
+impl StreamQuery {
+    pub fn codename(&self) -> &str {
+        &self.codename
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage<'a> {
+    Challenge {
+        nonce: &'a str,
+        setting: &'a str,
+    },
+    Code(&'a CodeEvent),
+    Resend(&'a CodeEvent),
+    Fr(&'a CodeEvent),
+    Job {
+        cookie: &'a str,
+        #[serde(flatten)]
+        code: &'a CodeEvent,
+    },
+    Close,
+}
+
+impl ServerMessage<'_> {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Challenge { .. } => "challenge",
+            Self::Code(_) => "code",
+            Self::Resend(_) => "resend",
+            Self::Fr(_) => "fr",
+            Self::Job { .. } => "job",
+            Self::Close => "close",
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&Envelope {
+            version: PROTOCOL_VERSION,
+            message: self,
+        })
+        .unwrap()
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    version: u32,
+    #[serde(flatten)]
+    message: &'a T,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Auth(Auth),
+    Result(RedeemReport),
+    Close,
+}
+
+impl TryFrom<&str> for ClientMessage {
+    type Error = serde_json::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        serde_json::from_str(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve_client_ip() {
+        let trusted = TrustedProxies::new(vec![
+            "127.0.0.0/8".parse().unwrap(),
+            "10.0.0.0/8".parse().unwrap(),
+        ]);
+        let proxy: IpAddr = "127.0.0.1".parse().unwrap();
+        let headers = |pairs: &[(&'static str, &str)]| {
+            let mut map = HeaderMap::new();
+            for (k, v) in pairs {
+                map.append(*k, v.parse().unwrap());
+            }
+            map
+        };
+
+        // Untrusted peer can not spoof header
+        let map = headers(&[("X-Real-IP", "1.1.1.1")]);
+        assert_eq!(
+            trusted.resolve("8.8.8.8".parse().unwrap(), &map),
+            "8.8.8.8".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            trusted.resolve(proxy, &map),
+            "1.1.1.1".parse::<IpAddr>().unwrap()
+        );
+
+        // Rightmost untrusted hop wins, spoofed leftmost entries are ignored
+        let map = headers(&[("X-Forwarded-For", "9.9.9.9, 2.2.2.2, 10.0.0.2")]);
+        assert_eq!(
+            trusted.resolve(proxy, &map),
+            "2.2.2.2".parse::<IpAddr>().unwrap()
+        );
+
+        let map = headers(&[
+            (
+                "Forwarded",
+                r#"for="[2001:db8::1]:4711";proto=https, for=10.1.1.1"#,
+            ),
+            ("X-Forwarded-For", "3.3.3.3"),
+        ]);
+        assert_eq!(
+            trusted.resolve(proxy, &map),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+
+        assert_eq!(trusted.resolve(proxy, &HeaderMap::new()), proxy);
+    }
+
+    #[test]
+    fn test_server_message() {
+        let event: CodeEvent = serde_json::from_value(serde_json::json!({
+            "code": "close", "message_id": 42, "timestamp": 1700000000, "seq": 3
+        }))
+        .unwrap();
+
+        let value: serde_json::Value =
+            serde_json::from_str(&ServerMessage::Code(&event).to_json()).unwrap();
+        assert_eq!(value["version"], PROTOCOL_VERSION);
+        assert_eq!(value["type"], "code");
+        assert_eq!(value["code"], "close");
+        assert_eq!(value["message_id"], 42);
+        assert_eq!(value["seq"], 3);
+
+        let value: serde_json::Value = serde_json::from_str(
+            &ServerMessage::Job {
+                cookie: "agent",
+                code: &event,
+            }
+            .to_json(),
+        )
+        .unwrap();
+        assert_eq!(value["type"], "job");
+        assert_eq!(value["cookie"], "agent");
+        assert_eq!(value["seq"], 3);
+
+        let value: serde_json::Value =
+            serde_json::from_str(&ServerMessage::Close.to_json()).unwrap();
+        assert_eq!(value["type"], "close");
+        assert!(value.get("code").is_none());
+    }
+
+    #[test]
+    fn test_client_message() {
+        assert!(matches!(
+            ClientMessage::try_from(r#"{"type": "close"}"#),
+            Ok(ClientMessage::Close)
+        ));
+        match ClientMessage::try_from(
+            r#"{"type": "auth", "codename": "a", "nonce": "b", "response": "c"}"#,
+        ) {
+            Ok(ClientMessage::Auth(auth)) => assert_eq!(auth.codename(), "a"),
+            other => panic!("Unexpected: {other:?}"),
+        }
+        match ClientMessage::try_from(
+            r#"{"type": "result", "code": "c", "codename": "a", "status": "fully_redeemed"}"#,
+        ) {
+            Ok(ClientMessage::Result(report)) => {
+                assert_eq!(report.status().error().as_deref(), Some("fully_redeemed"));
+                assert!(report.reward().is_none());
+            }
+            other => panic!("Unexpected: {other:?}"),
+        }
+        assert!(ClientMessage::try_from("close").is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use std::{sync::Arc, time::Duration};
+
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use sha2::Sha256;
+use tokio::sync::broadcast;
+
+use crate::{
+    config::Webhook,
+    database::{BroadcastEvent, DatabaseHelper},
+    web::types::ServerMessage,
+};
+
+const SIGNATURE_HEADER: &str = "X-Signature-256";
+
+pub struct Webhooks {
+    client: reqwest::Client,
+    hooks: Vec<Webhook>,
+    database: DatabaseHelper,
+    backoff: Duration,
+}
+
+impl Webhooks {
+    const TIMEOUT: Duration = Duration::from_secs(10);
+    const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+    pub fn new(hooks: Vec<Webhook>, database: DatabaseHelper) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .user_agent(concat!("code-forwarder/", env!("CARGO_PKG_VERSION")))
+                .timeout(Self::TIMEOUT)
+                .build()?,
+            hooks,
+            database,
+            backoff: Duration::from_secs(1),
+        })
+    }
+
+    pub async fn run(self, mut broadcast: broadcast::Receiver<BroadcastEvent>) {
+        let webhooks = Arc::new(self);
+        loop {
+            let (is_fr, code) = match broadcast.recv().await {
+                Ok(BroadcastEvent::NewCode(code)) => (false, code),
+                Ok(BroadcastEvent::Fr(code)) => (true, code),
+                Ok(BroadcastEvent::Resend(_)) => continue,
+                Ok(BroadcastEvent::Exit) | Err(broadcast::error::RecvError::Closed) => break,
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    warn!("Webhook lagged, {count} events skipped");
+                    continue;
+                }
+            };
+            let message = if is_fr {
+                ServerMessage::Fr(&code)
+            } else {
+                ServerMessage::Code(&code)
+            };
+            let event = message.kind();
+            let body = Arc::new(message.to_json());
+            for index in 0..webhooks.hooks.len() {
+                let webhooks = webhooks.clone();
+                let body = body.clone();
+                let code = code.code().to_string();
+                tokio::spawn(async move {
+                    webhooks
+                        .deliver(&webhooks.hooks[index], event, &code, &body)
+                        .await
+                });
+            }
+        }
+    }
+
+    async fn deliver(&self, hook: &Webhook, event: &'static str, code: &str, body: &str) {
+        for attempt in 1..=hook.retries() + 1 {
+            let (status, error) = match self.post(hook, body).await {
+                Ok(status) => (Some(status.as_u16()), None),
+                Err(e) => (e.status().map(|s| s.as_u16()), Some(e.to_string())),
+            };
+            if let Err(e) = self
+                .database
+                .webhook_log(
+                    hook.url().to_string(),
+                    event,
+                    code.to_string(),
+                    attempt,
+                    status,
+                    error.clone(),
+                )
+                .await
+            {
+                warn!("Record delivery of {code} to {} error: {e}", hook.url());
+            }
+
+            let Some(error) = error else {
+                info!("Delivered {event} {code} to {}", hook.url());
+                return;
+            };
+            warn!(
+                "Deliver {event} {code} to {} attempt {attempt} error: {error}",
+                hook.url()
+            );
+            if attempt <= hook.retries() {
+                tokio::time::sleep(self.backoff(attempt)).await;
+            }
+        }
+        error!("Give up delivering {event} {code} to {}", hook.url());
+    }
+
+    async fn post(&self, hook: &Webhook, body: &str) -> reqwest::Result<reqwest::StatusCode> {
+        let response = self
+            .client
+            .post(hook.url())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(
+                SIGNATURE_HEADER,
+                format!("sha256={}", sign(hook.secret(), body)),
+            )
+            .body(body.to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.status())
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(Self::MAX_BACKOFF)
+    }
+}
+
+// Hex HMAC-SHA256 of request body, receiver verifies it with the shared secret
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use axum::{
+        extract::State,
+        http::{HeaderMap, StatusCode},
+    };
+    use sqlx::Connection as _;
+
+    use super::{SIGNATURE_HEADER, Webhooks, sign};
+    use crate::{config::Webhook, database::testing::TestDatabase};
+
+    #[tokio::test]
+    async fn test_deliver_with_retry() {
+        let test = TestDatabase::new().await;
+        let database = &test.database;
+
+        // Stand-in receiver rejects bad signatures and fails the first delivery
+        let hits = Arc::new(AtomicUsize::new(0));
+        let router = axum::Router::new()
+            .route(
+                "/hook",
+                axum::routing::post(
+                    |State(hits): State<Arc<AtomicUsize>>, headers: HeaderMap, body: String| async move {
+                        if headers[SIGNATURE_HEADER] != format!("sha256={}", sign("secret", &body)) {
+                            return StatusCode::UNAUTHORIZED;
+                        }
+                        match hits.fetch_add(1, Ordering::SeqCst) {
+                            0 => StatusCode::INTERNAL_SERVER_ERROR,
+                            _ => StatusCode::NO_CONTENT,
+                        }
+                    },
+                ),
+            )
+            .with_state(hits.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let hook: Webhook = toml::from_str(&format!(
+            "url = \"http://{addr}/hook\"\nsecret = \"secret\"\nretries = 2"
+        ))
+        .unwrap();
+        let mut webhooks = Webhooks::new(vec![hook.clone()], database.clone()).unwrap();
+        webhooks.backoff = Duration::from_millis(10);
+        webhooks
+            .deliver(&hook, "code", "abcde", r#"{"type":"code","code":"abcde"}"#)
+            .await;
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        // Round trip through actor so queued delivery logs are written
+        database.v_query().await.unwrap();
+        let mut conn = sqlx::SqliteConnection::connect(test.file()).await.unwrap();
+        let rows: Vec<(u32, Option<u16>, Option<String>)> = sqlx::query_as(
+            r#"SELECT "attempt", "status", "error" FROM "webhook_deliveries" ORDER BY "id""#,
+        )
+        .fetch_all(&mut conn)
+        .await
+        .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!((rows[0].0, rows[0].1), (1, Some(500)));
+        assert!(rows[0].2.is_some());
+        assert_eq!(rows[1], (2, Some(204), None));
+
+        conn.close().await.ok();
+        test.close().await;
+    }
+}